@@ -0,0 +1,147 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::config::{env_opt, env_or};
+use crate::distances::vantage_point;
+use crate::monitor::sample;
+use crate::ping::Pinger;
+use crate::structs::{RecordWithGeolocation, VantagePoint};
+
+#[derive(Debug)]
+struct AgentConfig {
+    /// Base URL of the collector, e.g. `http://127.0.0.1:9300`.
+    collector: String,
+    interval: Duration,
+    /// Stop after this many rounds, run forever if unset.
+    rounds: Option<u64>,
+}
+
+impl AgentConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            collector: env_or("COLLECTOR_URL", "http://127.0.0.1:9300".to_string())?
+                .trim_end_matches('/')
+                .to_string(),
+            interval: Duration::from_secs(env_or("AGENT_INTERVAL_S", 300)?),
+            rounds: env_opt("AGENT_ROUNDS")?,
+        };
+        anyhow::ensure!(
+            !config.interval.is_zero(),
+            "AGENT_INTERVAL_S must be above 0"
+        );
+        Ok(config)
+    }
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+async fn fetch_targets(
+    client: &HttpClient,
+    config: &AgentConfig,
+) -> anyhow::Result<Vec<RecordWithGeolocation>> {
+    let response = client
+        .get(format!("{}/targets", config.collector).parse()?)
+        .await?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "collector answered {}",
+        response.status()
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Probes the targets and streams a sample per target to the collector as soon as it is
+/// done, so a long round shows up there while it runs.
+async fn round(
+    client: &HttpClient,
+    config: &AgentConfig,
+    pinger: &Pinger,
+    origin: &VantagePoint,
+) -> anyhow::Result<()> {
+    let records = fetch_targets(client, config).await?;
+    println!("probing {} targets", records.len());
+
+    let (mut sender, body) = Body::channel();
+    let request = Request::post(format!("{}/results", config.collector))
+        .header("Content-Type", "application/x-ndjson")
+        .header("X-Origin-Id", &origin.id)
+        .body(body)?;
+    let response = tokio::spawn(client.request(request));
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let tasks = records.into_iter().filter_map(|r| {
+        let country = r.country.clone();
+        let task = pinger.spawn(r)?;
+        Some(async move { (country, task.await) })
+    });
+    let mut results = stream::iter(tasks).buffer_unordered(pinger.max_in_flight());
+    let mut sent = 0;
+    while let Some((country, result)) = results.next().await {
+        let Some(mut sample) = sample(timestamp, country, result) else {
+            continue;
+        };
+        sample.origin = Some(origin.clone());
+        let mut line = serde_json::to_vec(&sample)?;
+        line.push(b'\n');
+        sender.send_data(line.into()).await?;
+        sent += 1;
+    }
+    drop(sender);
+
+    let response = response.await??;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    anyhow::ensure!(
+        status.is_success(),
+        "collector answered {}: {}",
+        status,
+        String::from_utf8_lossy(&body).trim()
+    );
+    println!("sent {sent} results to {}", config.collector);
+    Ok(())
+}
+
+/// Runs probing rounds on targets handed out by the collector and reports to it, as the
+/// origin configured with `ORIGIN_ID`, `ORIGIN_LATITUDE` and `ORIGIN_LONGITUDE`.
+pub async fn run_agent() -> anyhow::Result<()> {
+    let config = AgentConfig::from_env()?;
+    let origin = vantage_point()?;
+    println!(
+        "agent {} at {:.4}, {:.4} reporting to {}",
+        origin.id, origin.latitude, origin.longitude, config.collector
+    );
+    let client = Client::builder().build(HttpsConnector::new());
+    let pinger = Pinger::from_env()?;
+
+    let mut interval = time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut done = 0;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        tokio::select! {
+            // an unreachable collector should not end a long running agent
+            result = round(&client, &config, &pinger, &origin) => {
+                if let Err(e) = result {
+                    println!("Err: round {}", e);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        done += 1;
+        if config.rounds.is_some_and(|rounds| done >= rounds) {
+            break;
+        }
+    }
+
+    println!("stopped after {done} rounds");
+    Ok(())
+}
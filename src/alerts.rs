@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{fs::File, io::BufReader};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use tokio::time;
+
+use crate::config::{env_opt, env_or};
+use crate::structs::Sample;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// 95th percentile of all RTTs in a round, in ms.
+    P95Rtt,
+    /// Share of probes without an answer, in percent.
+    Loss,
+}
+
+/// A rule from the `ALERT_RULES` file, e.g. "p95 RTT to targets in DE above 40 ms for 10
+/// minutes" is `{"name": "de-slow", "metric": "p95_rtt", "above": 40, "for_minutes": 10,
+/// "country": "DE"}`.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: AlertMetric,
+    pub above: f64,
+    /// How long the threshold has to be exceeded before the rule fires.
+    #[serde(default)]
+    pub for_minutes: f64,
+    #[serde(default)]
+    pub country: Option<String>,
+    /// Only targets with this IP or location name.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Evaluate every matching target on its own instead of all of them together.
+    #[serde(default)]
+    pub per_target: bool,
+}
+
+impl AlertRule {
+    fn matches(&self, sample: &Sample) -> bool {
+        self.country.as_ref().is_none_or(|c| *c == sample.country)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|t| *t == sample.ip || *t == sample.location)
+    }
+}
+
+/// Nearest-rank percentile of sorted values, `p` between 0 and 100.
+pub fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+#[derive(Debug, Default)]
+struct Aggregate {
+    rtts: Vec<f64>,
+    sent: usize,
+    lost: usize,
+}
+
+impl Aggregate {
+    fn value(&mut self, metric: AlertMetric) -> Option<f64> {
+        match metric {
+            AlertMetric::P95Rtt => {
+                self.rtts.sort_by(f64::total_cmp);
+                percentile(&self.rtts, 95.).map(|rtt| rtt * 1000.)
+            }
+            AlertMetric::Loss => {
+                (self.sent > 0).then(|| self.lost as f64 / self.sent as f64 * 100.)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AlertState {
+    breaching_since: Option<u64>,
+    firing: bool,
+    /// Whether the current firing was sent, so its resolve is sent as well.
+    notified: bool,
+    last_notified: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Notification {
+    pub rule: String,
+    /// `firing` or `resolved`.
+    pub status: &'static str,
+    /// Target IP for `per_target` rules.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub metric: AlertMetric,
+    /// `None` if no probe was answered.
+    pub value: Option<f64>,
+    pub threshold: f64,
+    /// Unix time in seconds of the round that changed the state.
+    pub timestamp: u64,
+}
+
+/// Evaluates alert rules after every monitoring round. Only state changes are notified,
+/// and a rule that fires again within the cooldown is not notified again.
+pub struct Alerts {
+    rules: Vec<AlertRule>,
+    webhook: Option<String>,
+    cooldown: u64,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// Rule index and target of the current round.
+    round: HashMap<(usize, Option<String>), Aggregate>,
+    states: HashMap<(usize, Option<String>), AlertState>,
+}
+
+impl Alerts {
+    pub fn new(rules: Vec<AlertRule>, webhook: Option<String>, cooldown: Duration) -> Self {
+        Self {
+            rules,
+            webhook,
+            cooldown: cooldown.as_secs(),
+            client: Client::builder().build(HttpsConnector::new()),
+            round: HashMap::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    /// Reads the rules from `ALERT_RULES`, `None` if alerting is not configured.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(path) = env_opt::<String>("ALERT_RULES")? else {
+            return Ok(None);
+        };
+        let input = File::open(path)?;
+        let rules: Vec<AlertRule> = serde_json::from_reader(BufReader::new(input))?;
+        let webhook = env_opt("ALERT_WEBHOOK")?;
+        if webhook.is_none() {
+            println!("warning: ALERT_WEBHOOK is not set, alerts are only printed");
+        }
+        let cooldown = Duration::from_secs(env_or("ALERT_COOLDOWN_S", 900)?);
+        println!("{} alert rules loaded", rules.len());
+        Ok(Some(Self::new(rules, webhook, cooldown)))
+    }
+
+    /// Adds a sample of a target that was sent `sent` probes to the current round.
+    pub fn observe(&mut self, sample: &Sample, sent: u16) {
+        let (rtts, sent, lost) = match &sample.stats {
+            Some(stats) => (stats.samples.as_slice(), stats.sent, stats.lost()),
+            None => (&[][..], usize::from(sent), usize::from(sent)),
+        };
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(sample) {
+                continue;
+            }
+            let target = rule.per_target.then(|| sample.ip.clone());
+            let aggregate = self.round.entry((i, target)).or_default();
+            aggregate.rtts.extend_from_slice(rtts);
+            aggregate.sent += sent;
+            aggregate.lost += lost;
+        }
+    }
+
+    /// Closes the round that started at `timestamp` and returns the notifications due.
+    /// Rules without a matching sample in the round are not breaching, so they resolve
+    /// once their targets stop being probed.
+    pub fn evaluate(&mut self, timestamp: u64) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        let mut keys = self.states.keys().cloned().collect::<Vec<_>>();
+        keys.extend(
+            self.round
+                .keys()
+                .filter(|k| !self.states.contains_key(k))
+                .cloned(),
+        );
+        keys.sort();
+        for key in keys {
+            let rule = &self.rules[key.0];
+            let (value, breaching) = match self.round.remove(&key) {
+                Some(mut aggregate) => {
+                    let value = aggregate.value(rule.metric);
+                    // a target that did not answer at all is over any RTT budget
+                    (value, value.is_none_or(|v| v > rule.above))
+                }
+                None => (None, false),
+            };
+            let target = key.1.clone();
+            let state = self.states.entry(key).or_default();
+            let notification = |status| Notification {
+                rule: rule.name.clone(),
+                status,
+                target: target.clone(),
+                metric: rule.metric,
+                value,
+                threshold: rule.above,
+                timestamp,
+            };
+
+            if breaching {
+                let since = *state.breaching_since.get_or_insert(timestamp);
+                let pending = timestamp.saturating_sub(since) as f64 / 60. < rule.for_minutes;
+                if state.firing || pending {
+                    continue;
+                }
+                state.firing = true;
+                state.notified = state
+                    .last_notified
+                    .is_none_or(|last| timestamp.saturating_sub(last) >= self.cooldown);
+                if state.notified {
+                    state.last_notified = Some(timestamp);
+                    notifications.push(notification("firing"));
+                } else {
+                    println!("alert {} fired again within the cooldown", rule.name);
+                }
+            } else {
+                state.breaching_since = None;
+                if state.firing {
+                    state.firing = false;
+                    if state.notified {
+                        notifications.push(notification("resolved"));
+                    }
+                }
+            }
+        }
+        notifications
+    }
+
+    /// Prints the notifications and posts each of them as JSON to the webhook.
+    pub async fn notify(&self, notifications: &[Notification]) {
+        for n in notifications {
+            println!(
+                "alert {} {} {}: {} above {}",
+                n.rule,
+                n.target.as_deref().unwrap_or(""),
+                n.status,
+                n.value.map_or(
+                    if n.status == "firing" {
+                        "no answer"
+                    } else {
+                        "no samples"
+                    }
+                    .to_string(),
+                    |v| format!("{v:.1}")
+                ),
+                n.threshold
+            );
+            let Some(webhook) = &self.webhook else {
+                continue;
+            };
+            if let Err(e) = self.post(webhook, n).await {
+                println!("Err: alert webhook {}", e);
+            }
+        }
+    }
+
+    async fn post(&self, webhook: &str, notification: &Notification) -> anyhow::Result<()> {
+        let request = Request::post(webhook)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(notification)?))?;
+        let response =
+            time::timeout(Duration::from_secs(10), self.client.request(request)).await??;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "webhook answered {}",
+            response.status()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::structs::{ProbeMethod, RttStats};
+
+    type Received = Arc<Mutex<Vec<Value>>>;
+
+    /// Webhook that keeps every notification posted to it.
+    fn sink() -> (String, Received) {
+        let received = Received::default();
+        let make_service = {
+            let received = received.clone();
+            make_service_fn(move |_| {
+                let received = received.clone();
+                let service = service_fn(move |request: Request<Body>| {
+                    let received = received.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        received
+                            .lock()
+                            .unwrap()
+                            .push(serde_json::from_slice(&body).unwrap());
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                });
+                async move { Ok::<_, Infallible>(service) }
+            })
+        };
+        let server =
+            Server::bind(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).serve(make_service);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    fn rule(for_minutes: f64) -> AlertRule {
+        AlertRule {
+            name: "lossy".to_string(),
+            metric: AlertMetric::Loss,
+            above: 50.,
+            for_minutes,
+            country: Some("DE".to_string()),
+            target: None,
+            per_target: false,
+        }
+    }
+
+    /// A sample of 4 probes of which `answered` came back.
+    fn sample(answered: usize) -> Sample {
+        let stats = (answered > 0).then(|| RttStats {
+            min: 0.01,
+            avg: 0.01,
+            median: None,
+            max: 0.01,
+            stddev: 0.,
+            jitter: None,
+            loss: (4 - answered) as f64 / 4. * 100.,
+            sent: 4,
+            samples: vec![0.01; answered],
+        });
+        Sample {
+            timestamp: 0,
+            ip: "10.0.0.1".to_string(),
+            location: "Cologne".to_string(),
+            country: "DE".to_string(),
+            method: ProbeMethod::Icmp,
+            stats,
+            failure: None,
+            origin: None,
+        }
+    }
+
+    /// Runs a round at `timestamp` with the given samples and returns the statuses posted.
+    async fn round(
+        alerts: &mut Alerts,
+        received: &Received,
+        timestamp: u64,
+        samples: &[Sample],
+    ) -> Vec<String> {
+        for s in samples {
+            alerts.observe(s, 4);
+        }
+        let notifications = alerts.evaluate(timestamp);
+        alerts.notify(&notifications).await;
+        received
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|n| {
+                assert_eq!(n["rule"], "lossy");
+                assert_eq!(n["timestamp"], timestamp);
+                n["status"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fire_dedupe_and_resolve() {
+        let (url, received) = sink();
+        let mut alerts = Alerts::new(vec![rule(2.)], Some(url), Duration::ZERO);
+
+        // pending until the loss lasted 2 minutes
+        assert!(round(&mut alerts, &received, 0, &[sample(0)])
+            .await
+            .is_empty());
+        assert!(round(&mut alerts, &received, 60, &[sample(1)])
+            .await
+            .is_empty());
+        assert_eq!(
+            round(&mut alerts, &received, 120, &[sample(0)]).await,
+            ["firing"]
+        );
+        // still firing, nothing new
+        assert!(round(&mut alerts, &received, 180, &[sample(0)])
+            .await
+            .is_empty());
+        assert_eq!(
+            round(&mut alerts, &received, 240, &[sample(4)]).await,
+            ["resolved"]
+        );
+        assert!(round(&mut alerts, &received, 300, &[sample(4)])
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolves_without_samples() {
+        let (url, received) = sink();
+        let mut alerts = Alerts::new(vec![rule(0.)], Some(url), Duration::ZERO);
+
+        assert_eq!(
+            round(&mut alerts, &received, 0, &[sample(0)]).await,
+            ["firing"]
+        );
+        let elsewhere = Sample {
+            country: "NL".to_string(),
+            ..sample(0)
+        };
+        assert_eq!(
+            round(&mut alerts, &received, 60, &[elsewhere]).await,
+            ["resolved"]
+        );
+        assert!(round(&mut alerts, &received, 120, &[]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cooldown() {
+        let (url, received) = sink();
+        let mut alerts = Alerts::new(vec![rule(0.)], Some(url), Duration::from_secs(600));
+
+        assert_eq!(
+            round(&mut alerts, &received, 0, &[sample(0)]).await,
+            ["firing"]
+        );
+        assert_eq!(
+            round(&mut alerts, &received, 60, &[sample(4)]).await,
+            ["resolved"]
+        );
+        // flapping within the cooldown is neither fired nor resolved
+        assert!(round(&mut alerts, &received, 120, &[sample(0)])
+            .await
+            .is_empty());
+        assert!(round(&mut alerts, &received, 180, &[sample(4)])
+            .await
+            .is_empty());
+        assert_eq!(
+            round(&mut alerts, &received, 600, &[sample(0)]).await,
+            ["firing"]
+        );
+    }
+
+    #[tokio::test]
+    async fn clock_steps_back() {
+        let (url, received) = sink();
+        let mut alerts = Alerts::new(vec![rule(0.)], Some(url), Duration::from_secs(600));
+
+        assert_eq!(
+            round(&mut alerts, &received, 1000, &[sample(0)]).await,
+            ["firing"]
+        );
+        assert_eq!(
+            round(&mut alerts, &received, 1060, &[sample(4)]).await,
+            ["resolved"]
+        );
+        // an earlier time counts as no time passed, so this is still within the cooldown
+        assert!(round(&mut alerts, &received, 500, &[sample(0)])
+            .await
+            .is_empty());
+    }
+}
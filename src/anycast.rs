@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, HashSet};
+use std::{fs::File, io::BufReader};
+
+use rayon::prelude::*;
+
+use crate::cbg::{intersect, Disc};
+use crate::config::env_or;
+use crate::distances::{distance_km, vantage_point};
+use crate::feasibility::max_distance_km;
+use crate::merge::merged_file;
+use crate::structs::{RecordWithTime, VantagePoint};
+
+/// Output of the anycast stage, read by the analysis stages to label the flagged IPs.
+pub fn anycast_file() -> anyhow::Result<String> {
+    env_or("ANYCAST_FILE", "./anycast.json".to_string())
+}
+
+/// IPs flagged as anycast, empty if the anycast stage has not run.
+pub fn anycast_ips() -> anyhow::Result<HashSet<String>> {
+    let Ok(input) = File::open(anycast_file()?) else {
+        return Ok(HashSet::new());
+    };
+    let results: Vec<AnycastTarget> = serde_json::from_reader(BufReader::new(input))?;
+    Ok(results.into_iter().map(|r| r.ip).collect())
+}
+
+/// Two origins whose RTTs put the target in discs that do not touch.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Contradiction {
+    origins: (String, String),
+    /// RTTs from both origins in ms.
+    times: (f64, f64),
+    /// Distance between the origins in km.
+    separation: f64,
+    /// How far the target could be from both origins together in km.
+    reach: f64,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct AnycastTarget {
+    ip: String,
+    location: String,
+    origins: usize,
+    /// The pair of origins farthest apart relative to their reach, `None` if only three
+    /// or more origins together rule out a single location.
+    contradiction: Option<Contradiction>,
+    /// By how many km the least violating single location misses a disc.
+    violation: f64,
+}
+
+fn contradiction(measurements: &[(&VantagePoint, f64)]) -> Option<Contradiction> {
+    let mut worst: Option<Contradiction> = None;
+    for (i, (a, a_time)) in measurements.iter().enumerate() {
+        for (b, b_time) in &measurements[i + 1..] {
+            let separation = distance_km((a.latitude, a.longitude), (b.latitude, b.longitude));
+            let reach = max_distance_km(*a_time) + max_distance_km(*b_time);
+            if separation > reach
+                && worst
+                    .as_ref()
+                    .is_none_or(|w| separation - reach > w.separation - w.reach)
+            {
+                worst = Some(Contradiction {
+                    origins: (a.id.clone(), b.id.clone()),
+                    times: (a_time * 1000f64, b_time * 1000f64),
+                    separation,
+                    reach,
+                });
+            }
+        }
+    }
+    worst
+}
+
+/// Labels a target measured from several origins as anycast if no single location meets
+/// their RTTs by more than `tolerance` km.
+fn classify(
+    ip: &str,
+    records: &[&RecordWithTime],
+    here: &VantagePoint,
+    tolerance: f64,
+) -> Option<AnycastTarget> {
+    let measurements = records
+        .iter()
+        .map(|r| (r.origin.as_ref().unwrap_or(here), r.time))
+        .collect::<Vec<_>>();
+    let discs = measurements
+        .iter()
+        .map(|(o, time)| Disc {
+            center: (o.latitude, o.longitude),
+            radius: max_distance_km(*time),
+        })
+        .collect::<Vec<_>>();
+    // two contradicting origins already rule out every point, so this is only
+    // feasible if no pair or larger group does
+    let region = intersect(&discs)?;
+    if region.feasible {
+        return None;
+    }
+    // a pair of discs confirms what the grid found exactly, the best point between
+    // them misses both by half the gap; only three or more origins together rely
+    // on the grid alone
+    let contradiction = contradiction(&measurements);
+    let confirmed = match &contradiction {
+        Some(c) => (c.separation - c.reach) / 2.,
+        None => region.radius,
+    };
+    if confirmed <= tolerance {
+        return None;
+    }
+    Some(AnycastTarget {
+        ip: ip.to_string(),
+        location: records[0].location.clone(),
+        origins: records.len(),
+        contradiction,
+        violation: region.radius,
+    })
+}
+
+/// Flags targets whose RTTs from several origins can't all be met by one location under
+/// the speed-of-light bound, which means different origins reached different hosts.
+pub fn detect_anycast() -> anyhow::Result<()> {
+    let input = File::open(merged_file()?)?;
+    let records: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+    let here = vantage_point()?;
+    // RTTs slightly under the bound and the grid search of the intersection are not
+    // exact, so a few km of violation don't make a target anycast
+    let tolerance: f64 = env_or("ANYCAST_MIN_VIOLATION_KM", 100.)?;
+    anyhow::ensure!(
+        tolerance >= 0.,
+        "ANYCAST_MIN_VIOLATION_KM must not be negative"
+    );
+
+    let mut targets: BTreeMap<&str, Vec<&RecordWithTime>> = BTreeMap::new();
+    for r in &records {
+        targets.entry(&r.ip).or_default().push(r);
+    }
+    let multi_origin = targets.values().filter(|r| r.len() >= 2).count();
+
+    let mut results = targets
+        .into_par_iter()
+        .filter(|(_, records)| records.len() >= 2)
+        .filter_map(|(ip, records)| classify(ip, &records, &here, tolerance))
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.violation.total_cmp(&a.violation));
+
+    for r in &results {
+        match &r.contradiction {
+            Some(c) => println!(
+                "{} ({}): {:.1} ms from {} and {:.1} ms from {}, {:.0} km apart, reach {:.0} km",
+                r.ip,
+                r.location,
+                c.times.0,
+                c.origins.0,
+                c.times.1,
+                c.origins.1,
+                c.separation,
+                c.reach
+            ),
+            None => println!(
+                "{} ({}): no single location fits all {} origins, the closest misses by {:.0} km",
+                r.ip, r.location, r.origins, r.violation
+            ),
+        }
+    }
+    println!(
+        "{}/{} targets measured from several origins are anycast",
+        results.len(),
+        multi_origin
+    );
+
+    let output = File::create(anycast_file()?)?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::ProbeMethod;
+
+    fn origin(id: &str, latitude: f64, longitude: f64) -> VantagePoint {
+        VantagePoint {
+            id: id.to_string(),
+            latitude,
+            longitude,
+        }
+    }
+
+    fn record(origin: &VantagePoint, ms: f64) -> RecordWithTime {
+        RecordWithTime {
+            ip: "192.0.2.1".to_string(),
+            location: "Frankfurt".to_string(),
+            time: ms / 1000.,
+            stats: None,
+            method: ProbeMethod::Icmp,
+            http: None,
+            origin: Some(origin.clone()),
+        }
+    }
+
+    #[test]
+    fn worst_contradicting_pair() {
+        let fra = origin("fra", 50.11, 8.68);
+        let ams = origin("ams", 52.37, 4.90);
+        let syd = origin("syd", -33.87, 151.21);
+        // 1 ms reaches 100 km, Frankfurt and Amsterdam are about 360 km apart
+        let near = [(&fra, 0.002), (&ams, 0.002)];
+        assert!(contradiction(&near).is_none());
+
+        // Amsterdam is a bit farther from Sydney but reaches 1000 km
+        let c = contradiction(&[(&fra, 0.001), (&ams, 0.010), (&syd, 0.001)]).unwrap();
+        assert_eq!(c.origins, ("fra".to_string(), "syd".to_string()));
+        assert_eq!(c.times, (1., 1.));
+        assert_eq!(c.reach, 200.);
+        assert!((c.separation - 16_500.).abs() < 200., "{}", c.separation);
+    }
+
+    #[test]
+    fn violations_within_the_tolerance_are_not_anycast() {
+        let fra = origin("fra", 50.11, 8.68);
+        let ams = origin("ams", 52.37, 4.90);
+        let here = origin("here", 0., 0.);
+        // 2 * 150 km reach against about 360 km, each disc is missed by about 30 km
+        let slightly = [record(&fra, 1.5), record(&ams, 1.5)];
+        let slightly = slightly.iter().collect::<Vec<_>>();
+        assert!(classify("192.0.2.1", &slightly, &here, 100.).is_none());
+        let target = classify("192.0.2.1", &slightly, &here, 10.).unwrap();
+        let c = target.contradiction.unwrap();
+        assert!(((c.separation - c.reach) / 2. - 30.).abs() < 10.);
+
+        let far = [record(&fra, 0.5), record(&ams, 0.5)];
+        let far = far.iter().collect::<Vec<_>>();
+        let target = classify("192.0.2.1", &far, &here, 100.).unwrap();
+        assert_eq!(target.origins, 2);
+        assert!(target.violation > 100.);
+
+        let fits = [record(&fra, 3.), record(&ams, 3.)];
+        let fits = fits.iter().collect::<Vec<_>>();
+        assert!(classify("192.0.2.1", &fits, &here, 0.).is_none());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, HashMap};
+use std::{fs::File, io::BufReader};
+
+use crate::alerts::percentile;
+use crate::config::env_or;
+use crate::ping::{ping_ips, ProbeConfig};
+use crate::structs::{RecordWithFailure, RecordWithGeolocation, RecordWithTime};
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+struct Budget {
+    /// 95th percentile of all RTTs in ms.
+    max_p95_ms: Option<f64>,
+    /// Share of probes without an answer in percent.
+    max_loss: Option<f64>,
+    /// Share of targets that answered at all, between 0 and 1.
+    min_reachable: Option<f64>,
+}
+
+/// The `ASSERT_RULES` file, e.g. `{"max_p95_ms": 150, "min_reachable": 0.95, "countries":
+/// {"DE": {"max_p95_ms": 30}}}`. Country budgets apply to that country's targets in
+/// addition to the global one.
+#[derive(Debug, serde::Deserialize)]
+struct AssertRules {
+    #[serde(flatten)]
+    global: Budget,
+    #[serde(default)]
+    countries: BTreeMap<String, Budget>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Check {
+    /// `all` or a country code.
+    scope: String,
+    check: &'static str,
+    value: Option<f64>,
+    budget: f64,
+    passed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    rtts: Vec<f64>,
+    sent: usize,
+    lost: usize,
+    answered: usize,
+    failed: usize,
+}
+
+impl Totals {
+    fn add_time(&mut self, record: &RecordWithTime, count: usize) {
+        self.answered += 1;
+        match &record.stats {
+            Some(stats) => {
+                // summaries imported from other tools only have the minimum
+                if stats.samples.is_empty() {
+                    self.rtts.push(record.time);
+                } else {
+                    self.rtts.extend_from_slice(&stats.samples);
+                }
+                self.sent += stats.sent;
+                self.lost += stats.lost();
+            }
+            None => {
+                self.rtts.push(record.time);
+                self.sent += count;
+                self.lost += count.saturating_sub(1);
+            }
+        }
+    }
+
+    fn add_failure(&mut self, count: usize) {
+        self.failed += 1;
+        self.sent += count;
+        self.lost += count;
+    }
+
+    fn check(mut self, scope: &str, budget: &Budget) -> Vec<Check> {
+        self.rtts.sort_by(f64::total_cmp);
+        let p95 = percentile(&self.rtts, 95.).map(|rtt| rtt * 1000.);
+        let loss = (self.sent > 0).then(|| self.lost as f64 / self.sent as f64 * 100.);
+        let targets = self.answered + self.failed;
+        let reachable = (targets > 0).then(|| self.answered as f64 / targets as f64);
+
+        let mut checks = Vec::new();
+        let mut push = |check, value: Option<f64>, budget: f64, passed: fn(f64, f64) -> bool| {
+            checks.push(Check {
+                scope: scope.to_string(),
+                check,
+                value,
+                budget,
+                // nothing answered is over any RTT and loss budget
+                passed: value.is_some_and(|v| passed(v, budget)),
+            })
+        };
+        if let Some(max) = budget.max_p95_ms {
+            push("p95 RTT (ms)", p95, max, |v, b| v <= b);
+        }
+        if let Some(max) = budget.max_loss {
+            push("loss (%)", loss, max, |v, b| v <= b);
+        }
+        if let Some(min) = budget.min_reachable {
+            push("reachable", reachable, min, |v, b| v >= b);
+        }
+        checks
+    }
+}
+
+pub async fn assert_slos() -> anyhow::Result<()> {
+    let input = File::open(env_or("ASSERT_RULES", "./assertions.json".to_string())?)?;
+    let rules: AssertRules = serde_json::from_reader(BufReader::new(input))?;
+
+    // ASSERT_PING=false checks the results of an earlier ping stage instead
+    if env_or("ASSERT_PING", true)? {
+        ping_ips().await?;
+    }
+    let count = ProbeConfig::from_env()?.count as usize;
+
+    let input = File::open("./with_geolocations.json")?;
+    let targets: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+    let countries = targets
+        .into_iter()
+        .map(|t| (t.ip, t.country))
+        .collect::<HashMap<_, _>>();
+    let input = File::open("./with_times.json")?;
+    let times: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+    let input = File::open("./failures.json")?;
+    let failures: Vec<RecordWithFailure> = serde_json::from_reader(BufReader::new(input))?;
+
+    let mut all = Totals::default();
+    let mut per_country: HashMap<&str, Totals> = HashMap::new();
+    for r in &times {
+        all.add_time(r, count);
+        let country = countries.get(&r.ip).map_or("", |c| c.as_str());
+        if rules.countries.contains_key(country) {
+            per_country.entry(country).or_default().add_time(r, count);
+        }
+    }
+    for r in &failures {
+        all.add_failure(count);
+        if rules.countries.contains_key(r.country.as_str()) {
+            per_country
+                .entry(r.country.as_str())
+                .or_default()
+                .add_failure(count);
+        }
+    }
+
+    let mut checks = all.check("all", &rules.global);
+    for (country, budget) in &rules.countries {
+        let totals = per_country.remove(country.as_str()).unwrap_or_default();
+        checks.extend(totals.check(country, budget));
+    }
+
+    for c in &checks {
+        println!(
+            "{} {} {}: {} (budget {})",
+            if c.passed { "PASS" } else { "FAIL" },
+            c.scope,
+            c.check,
+            c.value.map_or("-".to_string(), |v| format!("{v:.2}")),
+            c.budget
+        );
+    }
+
+    let output = File::create("./assertions_report.json")?;
+    serde_json::to_writer_pretty(output, &checks)?;
+
+    let failed = checks.iter().filter(|c| !c.passed).count();
+    anyhow::ensure!(
+        failed == 0,
+        "{failed} of {} assertions failed",
+        checks.len()
+    );
+    println!("all {} assertions passed", checks.len());
+    Ok(())
+}
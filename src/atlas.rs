@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::cbg::Origin;
+use crate::config::env_or;
+use crate::imports::known_locations;
+use crate::ping::rtt_stats;
+use crate::structs::{Hop, ProbeMethod, RecordWithPath, RecordWithTime, VantagePoint};
+
+/// One entry of an Atlas result dump, `result` depends on the measurement type.
+#[derive(Debug, serde::Deserialize)]
+struct AtlasResult {
+    #[serde(rename = "type")]
+    kind: String,
+    prb_id: u64,
+    dst_addr: Option<String>,
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    sent: usize,
+    /// Paris traceroute variation, 0 for classic traceroute.
+    #[serde(default)]
+    paris_id: Option<u64>,
+    #[serde(default)]
+    result: serde_json::Value,
+}
+
+/// A ping reply, `{"x": "*"}` for a lost probe.
+#[derive(Debug, serde::Deserialize)]
+struct AtlasReply {
+    rtt: Option<f64>,
+    /// Set on a duplicate reply to a probe that was already answered.
+    dup: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AtlasHop {
+    hop: u8,
+    #[serde(default)]
+    result: Vec<AtlasTraceReply>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AtlasTraceReply {
+    from: Option<String>,
+    rtt: Option<f64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Geometry {
+    /// Longitude, latitude.
+    coordinates: Vec<f64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AtlasProbe {
+    id: u64,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    geometry: Option<Geometry>,
+}
+
+impl AtlasProbe {
+    fn coordinates(&self) -> Option<(f64, f64)> {
+        match (self.latitude, self.longitude, &self.geometry) {
+            (Some(latitude), Some(longitude), _) => Some((latitude, longitude)),
+            (_, _, Some(g)) => Some((*g.coordinates.get(1)?, *g.coordinates.first()?)),
+            _ => None,
+        }
+    }
+}
+
+/// The probe archive, an API response page or a plain list of probes.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum AtlasProbes {
+    Archive { objects: Vec<AtlasProbe> },
+    Api { results: Vec<AtlasProbe> },
+    List(Vec<AtlasProbe>),
+}
+
+fn load_probes(path: &str) -> anyhow::Result<HashMap<u64, (f64, f64)>> {
+    let input = File::open(path).with_context(|| {
+        format!(
+            "{path} not found, download the probe archive from \
+             https://ftp.ripe.net/ripe/atlas/probes/archive/"
+        )
+    })?;
+    let probes = match serde_json::from_reader(BufReader::new(input))? {
+        AtlasProbes::Archive { objects } => objects,
+        AtlasProbes::Api { results } => results,
+        AtlasProbes::List(probes) => probes,
+    };
+    Ok(probes
+        .into_iter()
+        .filter_map(|p| Some((p.id, p.coordinates()?)))
+        .collect())
+}
+
+/// Reads a JSON array as returned by the API or one result per line as downloaded.
+fn load_results(path: &str) -> anyhow::Result<Vec<AtlasResult>> {
+    let text = fs::read_to_string(path)?;
+    if text.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&text)?);
+    }
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Ok(serde_json::from_str(l)?))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct ProbeResults {
+    /// Target -> RTTs in seconds and probes sent, over all results.
+    pings: BTreeMap<String, (Vec<f64>, usize)>,
+    /// Target -> timestamp and the latest traceroute.
+    paths: BTreeMap<String, (u64, RecordWithPath)>,
+}
+
+/// RTTs in seconds of the answered probes of a ping result and the probes sent.
+fn ping_samples(result: serde_json::Value, sent: usize) -> anyhow::Result<(Vec<f64>, usize)> {
+    let replies: Vec<AtlasReply> = serde_json::from_value(result)?;
+    let replies = replies
+        .into_iter()
+        .filter(|r| r.dup.is_none())
+        .collect::<Vec<_>>();
+    let samples = replies
+        .iter()
+        .filter_map(|r| r.rtt.map(|rtt| rtt / 1000.))
+        .collect();
+    Ok((samples, sent.max(replies.len())))
+}
+
+fn hops(result: serde_json::Value) -> anyhow::Result<Vec<Hop>> {
+    let hops: Vec<AtlasHop> = serde_json::from_value(result)?;
+    Ok(hops
+        .into_iter()
+        .map(|h| {
+            let samples = h
+                .result
+                .iter()
+                .filter_map(|r| r.rtt.map(|rtt| rtt / 1000.))
+                .collect::<Vec<_>>();
+            let sent = h.result.len().max(1);
+            Hop {
+                ttl: h.hop,
+                address: h.result.iter().find_map(|r| r.from.clone()),
+                rtt: samples.iter().copied().reduce(f64::min),
+                loss: (sent - samples.len()) as f64 / sent as f64 * 100f64,
+                samples,
+            }
+        })
+        .collect())
+}
+
+/// Imports Atlas ping and traceroute results given as arguments. Every probe becomes an
+/// origin with its own times and paths files, listed in `ORIGINS` for the CBG stage.
+pub fn import_atlas() -> anyhow::Result<()> {
+    let paths = std::env::args().skip(2).collect::<Vec<_>>();
+    anyhow::ensure!(
+        !paths.is_empty(),
+        "usage: pinger import-atlas <results.json>..."
+    );
+    let probes = load_probes(&env_or("ATLAS_PROBES", "./atlas_probes.json".to_string())?)?;
+    let dir = env_or("ATLAS_DIR", PathBuf::from("./atlas"))?;
+    let origins_file = env_or("ORIGINS", "./origins.json".to_string())?;
+    let locations = known_locations()?;
+
+    let mut results: BTreeMap<u64, ProbeResults> = BTreeMap::new();
+    let mut skipped = 0;
+    for path in &paths {
+        for r in load_results(path)? {
+            let Some(dst) = r.dst_addr else {
+                skipped += 1;
+                continue;
+            };
+            let probe = results.entry(r.prb_id).or_default();
+            match r.kind.as_str() {
+                "ping" => {
+                    let (replies, replies_sent) = ping_samples(r.result, r.sent)?;
+                    let (samples, sent) = probe.pings.entry(dst).or_default();
+                    samples.extend(replies);
+                    *sent += replies_sent;
+                }
+                "traceroute" => {
+                    let hops = hops(r.result)?;
+                    let path = RecordWithPath {
+                        location: locations.get(&dst).cloned().unwrap_or_default(),
+                        reached: hops
+                            .iter()
+                            .any(|h| h.address.as_deref() == Some(dst.as_str())),
+                        ip: dst.clone(),
+                        paris: r.paris_id.is_some_and(|id| id > 0),
+                        hops,
+                        origin: None,
+                    };
+                    match probe.paths.get(&dst) {
+                        Some((timestamp, _)) if *timestamp > r.timestamp => {}
+                        _ => {
+                            probe.paths.insert(dst, (r.timestamp, path));
+                        }
+                    }
+                }
+                _ => skipped += 1,
+            }
+        }
+    }
+
+    fs::create_dir_all(&dir)?;
+    let mut origins = match File::open(&origins_file) {
+        Ok(input) => serde_json::from_reader::<_, Vec<Origin>>(BufReader::new(input))?,
+        Err(_) => Vec::new(),
+    };
+    for (prb_id, probe) in results {
+        let Some(&(latitude, longitude)) = probes.get(&prb_id) else {
+            println!("probe {prb_id} has no coordinates, skipping it");
+            continue;
+        };
+        let id = format!("atlas-{prb_id}");
+        let origin = VantagePoint {
+            id: id.clone(),
+            latitude,
+            longitude,
+        };
+
+        let times = probe
+            .pings
+            .into_iter()
+            .filter_map(|(ip, (samples, sent))| {
+                let stats = rtt_stats(samples, sent)?;
+                Some(RecordWithTime {
+                    location: locations.get(&ip).cloned().unwrap_or_default(),
+                    ip,
+                    time: stats.min,
+                    stats: Some(stats),
+                    method: ProbeMethod::Icmp,
+                    http: None,
+                    origin: Some(origin.clone()),
+                })
+            })
+            .collect::<Vec<_>>();
+        let paths = probe
+            .paths
+            .into_values()
+            .map(|(_, p)| RecordWithPath {
+                origin: Some(origin.clone()),
+                ..p
+            })
+            .collect::<Vec<_>>();
+        println!("{id}: {} targets, {} paths", times.len(), paths.len());
+
+        let times_path = dir.join(format!("with_times_{id}.json"));
+        serde_json::to_writer_pretty(File::create(&times_path)?, &times)?;
+        if !paths.is_empty() {
+            let paths_path = dir.join(format!("paths_{id}.json"));
+            serde_json::to_writer_pretty(File::create(paths_path)?, &paths)?;
+        }
+
+        origins.retain(|o| o.id != id);
+        origins.push(Origin {
+            id,
+            latitude,
+            longitude,
+            times: times_path.to_string_lossy().into_owned(),
+        });
+    }
+    if skipped > 0 {
+        println!("skipped {skipped} results of other types or without destination");
+    }
+
+    let output = File::create(&origins_file)?;
+    serde_json::to_writer_pretty(output, &origins)?;
+    println!("{} origins in {}", origins.len(), origins_file);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_result_without_duplicates() {
+        let line = r#"{"fw":5080,"lts":21,"dst_name":"192.0.2.1","af":4,"dst_addr":"192.0.2.1","src_addr":"10.0.0.2","proto":"ICMP","ttl":57,"size":48,"result":[{"rtt":11.2},{"rtt":9.0,"dup":1},{"x":"*"},{"rtt":11.6}],"msm_id":1001,"prb_id":6001,"timestamp":1697796000,"msm_name":"Ping","from":"198.51.100.7","type":"ping","group_id":1001,"step":240,"stored_timestamp":1697796002,"sent":3,"rcvd":2,"dup":1,"min":9.0,"max":11.6,"avg":10.6}"#;
+        let result: AtlasResult = serde_json::from_str(line).unwrap();
+        assert_eq!((result.kind.as_str(), result.prb_id), ("ping", 6001));
+        let (samples, sent) = ping_samples(result.result, result.sent).unwrap();
+        assert_eq!(sent, 3);
+        assert_eq!(samples.len(), 2);
+        assert!((samples[0] - 0.0112).abs() < 1e-9);
+        assert!((samples[1] - 0.0116).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+use std::{fs::File, io::BufReader};
+
+use rayon::prelude::*;
+
+use crate::anycast::anycast_ips;
+use crate::config::env_or;
+use crate::distances::{distance_km, vantage_point};
+use crate::feasibility::max_distance_km;
+use crate::geolocations::coordinates;
+use crate::merge::merged_file;
+use crate::structs::{RecordWithGeolocation, RecordWithTime};
+
+/// A measurement origin and the ping results collected from it.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Origin {
+    pub id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Path to a `with_times.json` produced on that machine.
+    pub times: String,
+}
+
+/// The area within `radius` km of `center`.
+#[derive(Debug, Clone, Copy)]
+pub struct Disc {
+    pub center: (f64, f64),
+    pub radius: f64,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Region {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Distance from the estimate to the farthest point of the region in km.
+    pub radius: f64,
+    /// Whether all discs actually intersect. If not, the estimate is the point that
+    /// violates them the least and `radius` is that violation.
+    pub feasible: bool,
+}
+
+const COARSE_STEP: f64 = 2.;
+const FINE_STEP: f64 = 0.1;
+const FINE_SPAN: f64 = 3.;
+
+/// How far `point` lies outside the farthest disc, negative if it is inside all of them.
+pub fn violation(discs: &[Disc], point: (f64, f64)) -> f64 {
+    discs
+        .iter()
+        .map(|d| distance_km(d.center, point) - d.radius)
+        .fold(f64::MIN, f64::max)
+}
+
+fn grid(
+    latitudes: (f64, f64),
+    longitudes: (f64, f64),
+    step: f64,
+) -> impl Iterator<Item = (f64, f64)> {
+    let lat_steps = ((latitudes.1 - latitudes.0) / step).round() as usize;
+    let lon_steps = ((longitudes.1 - longitudes.0) / step).round() as usize;
+    (0..=lat_steps).flat_map(move |i| {
+        (0..=lon_steps).map(move |j| {
+            let longitude = longitudes.0 + j as f64 * step;
+            // wrap around the antimeridian
+            let longitude = (longitude + 540.) % 360. - 180.;
+            ((latitudes.0 + i as f64 * step).clamp(-90., 90.), longitude)
+        })
+    })
+}
+
+/// Averages points on the sphere through their unit vectors.
+fn centroid(points: &[(f64, f64)]) -> (f64, f64) {
+    let (mut x, mut y, mut z) = (0f64, 0f64, 0f64);
+    for (lat, lon) in points {
+        let (lat, lon) = (lat.to_radians(), lon.to_radians());
+        x += lat.cos() * lon.cos();
+        y += lat.cos() * lon.sin();
+        z += lat.sin();
+    }
+    (
+        z.atan2((x * x + y * y).sqrt()).to_degrees(),
+        y.atan2(x).to_degrees(),
+    )
+}
+
+/// Intersects the discs on the globe with a coarse grid search refined around the best
+/// point, and summarises the intersection by its centroid and radius.
+pub fn intersect(discs: &[Disc]) -> Option<Region> {
+    if discs.is_empty() {
+        return None;
+    }
+
+    let scored = |points: Vec<(f64, f64)>| {
+        points
+            .into_iter()
+            .map(|p| (p, violation(discs, p)))
+            .collect::<Vec<_>>()
+    };
+
+    let coarse = scored(grid((-90., 90.), (-180., 180.), COARSE_STEP).collect());
+    let (best, _) = *coarse
+        .iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .expect("grid is not empty");
+
+    let mut inside = coarse
+        .iter()
+        .filter(|(_, v)| *v <= 0.)
+        .map(|(p, _)| *p)
+        .collect::<Vec<_>>();
+
+    if inside.len() < 2 {
+        // the intersection is smaller than the coarse grid, look closer
+        let fine = scored(
+            grid(
+                (best.0 - FINE_SPAN, best.0 + FINE_SPAN),
+                (best.1 - FINE_SPAN, best.1 + FINE_SPAN),
+                FINE_STEP,
+            )
+            .collect(),
+        );
+        inside = fine
+            .iter()
+            .filter(|(_, v)| *v <= 0.)
+            .map(|(p, _)| *p)
+            .collect();
+
+        if inside.is_empty() {
+            let (point, violation) = *fine
+                .iter()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("grid is not empty");
+            return Some(Region {
+                latitude: point.0,
+                longitude: point.1,
+                radius: violation,
+                feasible: false,
+            });
+        }
+    }
+
+    let center = centroid(&inside);
+    let radius = inside
+        .iter()
+        .map(|p| distance_km(center, *p))
+        .fold(0f64, f64::max);
+
+    Some(Region {
+        latitude: center.0,
+        longitude: center.1,
+        radius,
+        feasible: true,
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CbgEstimate {
+    ip: String,
+    origins: usize,
+    estimate: Region,
+    provider_location: Option<String>,
+    provider_latitude: Option<f64>,
+    provider_longitude: Option<f64>,
+    /// Distance between the estimate and the provider's location in km.
+    provider_error: Option<f64>,
+    /// Whether the provider's location satisfies every measured constraint.
+    provider_consistent: Option<bool>,
+    /// Flagged by the anycast stage, the estimate is meaningless.
+    anycast: bool,
+}
+
+pub fn load_origins(path: &str) -> anyhow::Result<Vec<Origin>> {
+    let input = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(input))?)
+}
+
+/// Constrains every target measured from at least `CBG_MIN_ORIGINS` origins of the
+/// merged dataset to the intersection of their discs.
+pub fn estimate_locations() -> anyhow::Result<()> {
+    let input = File::open(merged_file()?)?;
+    let records: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+    let min_origins: usize = env_or("CBG_MIN_ORIGINS", 2)?;
+    // records without an origin were measured here
+    let here = vantage_point()?;
+
+    let mut per_origin: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut discs: HashMap<String, Vec<Disc>> = HashMap::new();
+    for r in &records {
+        let origin = r.origin.as_ref().unwrap_or(&here);
+        *per_origin.entry(&origin.id).or_default() += 1;
+        discs.entry(r.ip.clone()).or_default().push(Disc {
+            center: (origin.latitude, origin.longitude),
+            radius: max_distance_km(r.time),
+        });
+    }
+    for (id, n) in &per_origin {
+        println!("{id}: {n} records");
+    }
+
+    let input = File::open("./with_geolocations.json")?;
+    let geolocations: Vec<RecordWithGeolocation> =
+        serde_json::from_reader(BufReader::new(input))?;
+    let geolocations = geolocations
+        .into_iter()
+        .map(|g| (g.ip.clone(), g))
+        .collect::<HashMap<_, _>>();
+    let anycast = anycast_ips()?;
+
+    let mut results = discs
+        .into_par_iter()
+        .filter(|(_, d)| d.len() >= min_origins)
+        .filter_map(|(ip, discs)| {
+            let estimate = intersect(&discs)?;
+            let provider = geolocations.get(&ip);
+            let provider_coordinates = provider.and_then(coordinates);
+            Some(CbgEstimate {
+                origins: discs.len(),
+                estimate,
+                provider_location: provider.map(|g| g.location.clone()),
+                provider_latitude: provider_coordinates.map(|c| c.0),
+                provider_longitude: provider_coordinates.map(|c| c.1),
+                provider_error: provider_coordinates
+                    .map(|c| distance_km(c, (estimate.latitude, estimate.longitude))),
+                provider_consistent: provider_coordinates.map(|c| violation(&discs, c) <= 0.),
+                anycast: anycast.contains(&ip),
+                ip,
+            })
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| {
+        b.provider_error
+            .unwrap_or_default()
+            .total_cmp(&a.provider_error.unwrap_or_default())
+    });
+
+    let inconsistent = results
+        .iter()
+        .filter(|r| r.provider_consistent == Some(false))
+        .count();
+    println!(
+        "estimated {} targets, provider location contradicts measurements for {inconsistent}",
+        results.len()
+    );
+
+    let output = File::create("./cbg.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRANKFURT: (f64, f64) = (50.11, 8.68);
+
+    /// Discs around vantage points in Hamburg, Munich and Paris that allow `slack` km
+    /// more than their distance to Frankfurt.
+    fn discs(slack: f64) -> Vec<Disc> {
+        [(53.55, 9.99), (48.14, 11.58), (48.86, 2.35)]
+            .into_iter()
+            .map(|center| Disc {
+                center,
+                radius: distance_km(center, FRANKFURT) + slack,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn estimate_within_the_grid_resolution() {
+        // a fine grid cell is 0.1 degrees on each side
+        let resolution = distance_km(
+            FRANKFURT,
+            (FRANKFURT.0 + FINE_STEP, FRANKFURT.1 + FINE_STEP),
+        );
+        let region = intersect(&discs(10.)).unwrap();
+        assert!(region.feasible);
+        let error = distance_km(FRANKFURT, (region.latitude, region.longitude));
+        assert!(error < resolution, "{error} km off");
+        assert!(region.radius < 2. * resolution, "{} km", region.radius);
+        assert!(violation(&discs(10.), FRANKFURT) <= 0.);
+    }
+
+    #[test]
+    fn impossible_rtts() {
+        // every disc ends 100 km short of the target
+        let discs = discs(-100.);
+        let region = intersect(&discs).unwrap();
+        assert!(!region.feasible);
+        assert!(region.radius > 0.);
+        assert_eq!(
+            region.radius,
+            violation(&discs, (region.latitude, region.longitude))
+        );
+
+        assert!(intersect(&[]).is_none());
+    }
+}
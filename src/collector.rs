@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use crate::cbg::Origin;
+use crate::config::env_or;
+use crate::merge::merged_file;
+use crate::structs::{RecordWithFailure, RecordWithTime, Sample, VantagePoint};
+
+#[derive(Debug)]
+struct CollectorConfig {
+    addr: SocketAddr,
+    /// Targets handed out to the agents, re-read on every request.
+    targets: String,
+    /// Per-origin times and failures files.
+    dir: PathBuf,
+    origins: String,
+    merged: String,
+}
+
+impl CollectorConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            addr: env_or("COLLECTOR_ADDR", "127.0.0.1:9300".parse()?)?,
+            targets: env_or("COLLECTOR_TARGETS", "./with_geolocations.json".to_string())?,
+            dir: env_or("COLLECTOR_DIR", PathBuf::from("./collector"))?,
+            origins: env_or("ORIGINS", "./origins.json".to_string())?,
+            merged: merged_file()?,
+        })
+    }
+}
+
+/// Latest result of every target from every origin.
+#[derive(Debug, Default)]
+struct Collected {
+    origins: BTreeMap<String, VantagePoint>,
+    times: BTreeMap<(String, String), RecordWithTime>,
+    failures: BTreeMap<(String, String), RecordWithFailure>,
+}
+
+impl Collected {
+    /// Starts from the origins, merged dataset and failures of an earlier run, if there
+    /// is one.
+    fn load(config: &CollectorConfig) -> anyhow::Result<Self> {
+        let mut collected = Self::default();
+        let Ok(input) = File::open(&config.origins) else {
+            return Ok(collected);
+        };
+        let origins: Vec<Origin> = serde_json::from_reader(BufReader::new(input))?;
+        for o in origins {
+            if !valid_origin_id(&o.id) {
+                println!("skipping origin {:?} of {}", o.id, config.origins);
+                continue;
+            }
+            let vantage = VantagePoint {
+                id: o.id.clone(),
+                latitude: o.latitude,
+                longitude: o.longitude,
+            };
+            collected.origins.insert(o.id, vantage);
+        }
+        if let Ok(input) = File::open(&config.merged) {
+            let records: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+            for r in records {
+                let Some(origin) = r.origin.clone().filter(|o| valid_origin_id(&o.id)) else {
+                    continue;
+                };
+                collected.origins.insert(origin.id.clone(), origin.clone());
+                collected.times.insert((origin.id, r.ip.clone()), r);
+            }
+        }
+        // origins whose targets all failed only show up here
+        for id in collected.origins.keys() {
+            let Ok(input) = File::open(config.dir.join(format!("failures_{id}.json"))) else {
+                continue;
+            };
+            let failures: Vec<RecordWithFailure> = serde_json::from_reader(BufReader::new(input))?;
+            for f in failures {
+                collected.failures.insert((id.clone(), f.ip.clone()), f);
+            }
+        }
+        println!(
+            "continuing {} with {} results from {} origins",
+            config.origins,
+            collected.times.len() + collected.failures.len(),
+            collected.origins.len()
+        );
+        Ok(collected)
+    }
+
+    fn add(&mut self, sample: Sample) -> anyhow::Result<()> {
+        let origin = sample
+            .origin
+            .ok_or_else(|| anyhow::anyhow!("sample of {} has no origin", sample.ip))?;
+        // the id names the origin's files
+        anyhow::ensure!(
+            valid_origin_id(&origin.id),
+            "invalid origin id {:?}, use letters, digits, '.', '_' and '-'",
+            origin.id
+        );
+        let key = (origin.id.clone(), sample.ip.clone());
+        self.origins.insert(origin.id.clone(), origin.clone());
+        match (sample.stats, sample.failure) {
+            (Some(stats), _) => {
+                self.failures.remove(&key);
+                self.times.insert(
+                    key,
+                    RecordWithTime {
+                        ip: sample.ip,
+                        location: sample.location,
+                        time: stats.min,
+                        stats: Some(stats),
+                        method: sample.method,
+                        http: None,
+                        origin: Some(origin),
+                    },
+                );
+            }
+            (None, Some(reason)) => {
+                self.times.remove(&key);
+                self.failures.insert(
+                    key,
+                    RecordWithFailure {
+                        ip: sample.ip,
+                        location: sample.location,
+                        country: sample.country,
+                        method: sample.method,
+                        reason,
+                    },
+                );
+            }
+            (None, None) => anyhow::bail!("sample of {} has neither stats nor failure", sample.ip),
+        }
+        Ok(())
+    }
+
+    /// Writes the times and failures of every origin, lists the origins for the CBG stage
+    /// and writes all times as one dataset.
+    fn write(&self, config: &CollectorConfig) -> anyhow::Result<()> {
+        fs::create_dir_all(&config.dir)?;
+        let mut origins = Vec::new();
+        for (id, vantage) in &self.origins {
+            let times = self
+                .times
+                .range((id.clone(), String::new())..)
+                .take_while(|((origin, _), _)| origin == id)
+                .map(|(_, r)| r)
+                .collect::<Vec<_>>();
+            let failures = self
+                .failures
+                .range((id.clone(), String::new())..)
+                .take_while(|((origin, _), _)| origin == id)
+                .map(|(_, r)| r)
+                .collect::<Vec<_>>();
+
+            let times_path = config.dir.join(format!("with_times_{id}.json"));
+            serde_json::to_writer_pretty(File::create(&times_path)?, &times)?;
+            let failures_path = config.dir.join(format!("failures_{id}.json"));
+            serde_json::to_writer_pretty(File::create(failures_path)?, &failures)?;
+
+            origins.push(Origin {
+                id: id.clone(),
+                latitude: vantage.latitude,
+                longitude: vantage.longitude,
+                times: times_path.to_string_lossy().into_owned(),
+            });
+        }
+        serde_json::to_writer_pretty(File::create(&config.origins)?, &origins)?;
+
+        let merged = self.times.values().collect::<Vec<_>>();
+        serde_json::to_writer_pretty(File::create(&config.merged)?, &merged)?;
+        Ok(())
+    }
+}
+
+/// Whether `id` can be used in a file name, like `fra-1` or `atlas-123`.
+fn valid_origin_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+fn lock(collected: &Mutex<Collected>) -> anyhow::Result<MutexGuard<'_, Collected>> {
+    collected
+        .lock()
+        .map_err(|_| anyhow::anyhow!("results were left inconsistent by a failed request"))
+}
+
+/// Adds one line of JSON, returns whether it held a sample.
+fn add_line(collected: &Mutex<Collected>, line: &[u8]) -> anyhow::Result<bool> {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return Ok(false);
+    }
+    let sample: Sample = serde_json::from_slice(line)?;
+    lock(collected)?.add(sample)?;
+    Ok(true)
+}
+
+/// Longest line accepted, a sample is well below that.
+const MAX_LINE: usize = 1 << 20;
+
+/// Reads one JSON sample per line as the agent sends them.
+async fn receive(
+    collected: &Mutex<Collected>,
+    body: &mut Body,
+    received: &mut usize,
+) -> anyhow::Result<()> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buffer.extend_from_slice(&chunk?);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<_>>();
+            *received += add_line(collected, &line)? as usize;
+        }
+        anyhow::ensure!(
+            buffer.len() <= MAX_LINE,
+            "line longer than {MAX_LINE} bytes"
+        );
+    }
+    *received += add_line(collected, &buffer)? as usize;
+    Ok(())
+}
+
+async fn handle(
+    config: Arc<CollectorConfig>,
+    collected: Arc<Mutex<Collected>>,
+    mut request: Request<Body>,
+) -> hyper::http::Result<Response<Body>> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/targets") => match fs::read(&config.targets) {
+            Ok(targets) => Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(targets)),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("{}: {e}\n", config.targets))),
+        },
+        (&Method::POST, "/results") => {
+            let peer = request
+                .headers()
+                .get("X-Origin-Id")
+                .and_then(|id| id.to_str().ok())
+                .unwrap_or("unknown agent")
+                .to_string();
+            let mut received = 0;
+            let result = receive(&collected, request.body_mut(), &mut received).await;
+            // what arrived before an error is kept as well, the files are rewritten off
+            // the runtime threads
+            let written = tokio::task::spawn_blocking(move || lock(&collected)?.write(&config));
+            let result = match written.await {
+                Ok(written) => result.and(written),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => {
+                    println!("{peer}: {received} results");
+                    Response::builder().body(Body::from(format!("{received}\n")))
+                }
+                Err(e) => {
+                    println!("Err: results from {peer} after {received} {e}");
+                    Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(format!("{e}\n")))
+                }
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found\n")),
+    }
+}
+
+/// Hands out the target list to agents and merges the results they stream back into
+/// per-origin files, `ORIGINS` and the merged dataset.
+pub async fn collect() -> anyhow::Result<()> {
+    let config = Arc::new(CollectorConfig::from_env()?);
+    let collected = Arc::new(Mutex::new(Collected::load(&config)?));
+
+    let make_service = {
+        let (config, collected) = (config.clone(), collected.clone());
+        make_service_fn(move |_| {
+            let (config, collected) = (config.clone(), collected.clone());
+            let service =
+                service_fn(move |request| handle(config.clone(), collected.clone(), request));
+            async move { Ok::<_, Infallible>(service) }
+        })
+    };
+    let server = Server::try_bind(&config.addr)?.serve(make_service);
+    println!(
+        "collecting on http://{}, targets from {}",
+        server.local_addr(),
+        config.targets
+    );
+    server
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+
+    let collected = lock(&collected)?;
+    println!(
+        "stopped with {} results from {} origins",
+        collected.times.len() + collected.failures.len(),
+        collected.origins.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::{env, str::FromStr};
+
+use anyhow::Context;
+
+/// Reads `key` from the environment (or `.env`), falling back to `default` when unset.
+pub fn env_or<T>(key: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(key) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid value for {key}: {value}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Reads a comma separated list from the environment, falling back to `default` when unset.
+pub fn env_list(key: &str, default: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Reads an optional setting from the environment, `None` when unset or empty.
+pub fn env_opt<T>(key: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(key) {
+        Ok(value) if !value.trim().is_empty() => Ok(Some(
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid value for {key}: {value}"))?,
+        )),
+        _ => Ok(None),
+    }
+}
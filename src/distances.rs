@@ -1,37 +1,113 @@
-use std::{fs::File, io::BufReader};
+use std::{env, fs::File, io::BufReader};
 
-use crate::structs::{RecordWithTime, RecordWithDistance};
+use crate::anycast::anycast_ips;
+use crate::config::env_or;
+use crate::structs::{RecordWithTime, RecordWithDistance, RttStats, VantagePoint};
+
+/// Where the measurements were taken, defaults to Cologne.
+pub fn origin() -> anyhow::Result<(f64, f64)> {
+    Ok((
+        env_or("ORIGIN_LATITUDE", 50.9375)?,
+        env_or("ORIGIN_LONGITUDE", 6.9603)?,
+    ))
+}
+
+/// Id and coordinates of this machine, `ORIGIN_ID` defaults to the host name.
+pub fn vantage_point() -> anyhow::Result<VantagePoint> {
+    let id = match env::var("ORIGIN_ID") {
+        Ok(id) => id,
+        Err(_) => dns_lookup::get_hostname().unwrap_or_else(|_| "local".to_string()),
+    };
+    let (latitude, longitude) = origin()?;
+    Ok(VantagePoint {
+        id,
+        latitude,
+        longitude,
+    })
+}
+
+/// Input of the distance stage, `TIMES_FILE` allows running it on e.g. http results.
+pub fn times_file() -> anyhow::Result<String> {
+    env_or("TIMES_FILE", "./with_times.json".to_string())
+}
+
+/// Output of the distance stage and input of the analysis and plotting stages.
+pub fn distances_file() -> anyhow::Result<String> {
+    env_or("DISTANCES_FILE", "./with_distances.json".to_string())
+}
 
 pub async fn calculate_distances() -> anyhow::Result<()> {
-    let input = File::open("./with_times.json")?;
+    let input = File::open(times_file()?)?;
     let records: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+    // min is the best estimate of propagation delay; files without statistics only have it,
+    // imported summaries have no median
+    let rtt: fn(&RttStats) -> Option<f64> = match env_or("RTT_STAT", "min".to_string())?.as_str()
+    {
+        "min" => |s| Some(s.min),
+        "avg" => |s| Some(s.avg),
+        "median" => |s| s.median,
+        "max" => |s| Some(s.max),
+        other => anyhow::bail!("unknown RTT_STAT: {other}"),
+    };
+
+    // records without an origin were measured here
+    let here = vantage_point()?;
+    let anycast = anycast_ips()?;
 
-    let mut cities = cities::all().to_vec();
-    cities.sort_by(|a, b| a.city.cmp(b.city));
+    let cities = Cities::new();
 
     let results = records
         .iter()
         .filter_map(|r| {
-            // binary search does not work because list is not sorted by city names
-            if let Ok(idx) = cities.binary_search_by(|c| c.city.cmp(r.location.as_str())) {
-                let city = &cities[idx];
-                let distance = geoutils::Location::new(city.latitude, city.longitude)
-                    .distance_to(&geoutils::Location::new(50.9375, 6.9603))
-                    .unwrap();
-                Some(RecordWithDistance {
-                    ip: r.ip.clone(),
-                    location: r.location.clone(),
-                    time: r.time,
-                    distance: distance.meters() / 1000f64,
-                })
-            } else {
-                None
-            }
+            let city = cities.coordinates(&r.location)?;
+            let origin = r.origin.clone().unwrap_or_else(|| here.clone());
+            Some(RecordWithDistance {
+                ip: r.ip.clone(),
+                location: r.location.clone(),
+                time: r.stats.as_ref().and_then(rtt).unwrap_or(r.time),
+                distance: geodesic_km(city, (origin.latitude, origin.longitude)),
+                origin: Some(origin),
+                anycast: anycast.contains(&r.ip),
+            })
         })
         .collect::<Vec<_>>();
 
-    let output = File::create("./with_distances.json")?;
+    let output = File::create(distances_file()?)?;
     serde_json::to_writer_pretty(output, &results)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The city list sorted by name, to place targets by their location name.
+pub struct Cities(Vec<&'static cities::City>);
+
+impl Cities {
+    pub fn new() -> Self {
+        // binary search does not work because list is not sorted by city names
+        let mut cities = cities::all().iter().collect::<Vec<_>>();
+        cities.sort_by(|a, b| a.city.cmp(b.city));
+        Self(cities)
+    }
+
+    /// `(latitude, longitude)` of one of the cities called `name`.
+    pub fn coordinates(&self, name: &str) -> Option<(f64, f64)> {
+        let idx = self.0.binary_search_by(|c| c.city.cmp(name)).ok()?;
+        Some((self.0[idx].latitude, self.0[idx].longitude))
+    }
+}
+
+/// Distance in km on the ellipsoid (Vincenty) between two `(latitude, longitude)` pairs,
+/// the great circle for nearly antipodal points where it does not converge.
+pub fn geodesic_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    geoutils::Location::new(from.0, from.1)
+        .distance_to(&geoutils::Location::new(to.0, to.1))
+        .map_or_else(|_| distance_km(from, to), |d| d.meters() / 1000f64)
+}
+
+/// Great-circle distance in km between two `(latitude, longitude)` pairs.
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    geoutils::Location::new(from.0, from.1)
+        .haversine_distance_to(&geoutils::Location::new(to.0, to.1))
+        .meters()
+        / 1000f64
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs::File, io::BufReader};
+
+use crate::probers::RawIcmpSocket;
+use crate::structs::{FailureReason, RecordWithFailure};
+use crate::traceroute::{parse_reply, ReplyKind};
+
+/// Classifies an ICMP destination unreachable `code`.
+pub fn classify_unreachable(v6: bool, code: u8) -> FailureReason {
+    // v4: network/host/communication administratively prohibited, v6: communication
+    // administratively prohibited and reject route
+    let prohibited = if v6 {
+        matches!(code, 1 | 6)
+    } else {
+        matches!(code, 9 | 10 | 13)
+    };
+    if prohibited {
+        FailureReason::AdminProhibited { code }
+    } else {
+        FailureReason::Unreachable { code: Some(code) }
+    }
+}
+
+/// Classifies an ICMP message that arrived instead of an echo reply.
+pub fn classify_icmp(v6: bool, icmp_type: u8, code: u8) -> FailureReason {
+    match (v6, icmp_type) {
+        (false, 3) | (true, 1) => classify_unreachable(v6, code),
+        (false, 11) | (true, 3) => FailureReason::TtlExceeded,
+        _ => FailureReason::SocketError {
+            message: format!("unexpected ICMP type {icmp_type} code {code}"),
+        },
+    }
+}
+
+pub fn classify_io(e: &io::Error) -> FailureReason {
+    match e.kind() {
+        ErrorKind::TimedOut => FailureReason::Timeout,
+        ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => {
+            FailureReason::Unreachable { code: None }
+        }
+        ErrorKind::PermissionDenied => FailureReason::AdminProhibited { code: 0 },
+        _ => FailureReason::SocketError {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Picks the reason reported for a target: anything is more telling than a timeout. Ties
+/// go to the reason declared last, so the result does not depend on hashing.
+pub fn dominant(reasons: Vec<FailureReason>) -> FailureReason {
+    let mut counts: BTreeMap<FailureReason, usize> = BTreeMap::new();
+    for r in reasons {
+        *counts.entry(r).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(r, n)| (*r != FailureReason::Timeout, *n))
+        .map(|(r, _)| r)
+        .unwrap_or(FailureReason::Timeout)
+}
+
+#[derive(Debug)]
+struct ReportedError {
+    identifier: u16,
+    received: Instant,
+    reason: FailureReason,
+}
+
+/// Errors by quoted destination and sequence.
+type Errors = HashMap<(IpAddr, u16), Vec<ReportedError>>;
+
+/// Collects ICMP errors sent by routers on the way. The ping client only matches replies
+/// from the target itself, so without this every filtered probe would look like a
+/// timeout. Needs raw sockets; the errors are keyed by the destination, identifier and
+/// sequence of the request they quote and dropped once the probe has timed out.
+#[derive(Clone)]
+pub struct ErrorListener {
+    errors: Arc<Mutex<Errors>>,
+    timeout: Duration,
+}
+
+impl ErrorListener {
+    pub fn spawn(timeout: Duration) -> Option<Self> {
+        let listener = Self {
+            errors: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        };
+        let families: [IpAddr; 2] = [[0u8; 4].into(), [0u16; 8].into()];
+        let mut listening = false;
+        for family in families {
+            let Ok(socket) = RawIcmpSocket::open(family) else {
+                continue;
+            };
+            let listener = listener.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1500];
+                let mut swept = Instant::now();
+                while let Ok((size, _)) = socket.recv_from(&mut buf).await {
+                    let Some(reply) = parse_reply(family, &buf[..size]) else {
+                        continue;
+                    };
+                    let reason = match reply.kind {
+                        ReplyKind::TimeExceeded => FailureReason::TtlExceeded,
+                        ReplyKind::Unreachable(code) => {
+                            classify_unreachable(family.is_ipv6(), code)
+                        }
+                        ReplyKind::EchoReply => continue,
+                    };
+                    let Some(destination) = reply.destination else {
+                        continue;
+                    };
+                    let now = Instant::now();
+                    let Ok(mut errors) = listener.errors.lock() else {
+                        break;
+                    };
+                    errors
+                        .entry((destination, reply.sequence))
+                        .or_default()
+                        .push(ReportedError {
+                            identifier: reply.identifier,
+                            received: now,
+                            reason,
+                        });
+                    if now.duration_since(swept) >= listener.timeout {
+                        listener.evict(&mut errors, now);
+                        swept = now;
+                    }
+                }
+            });
+            listening = true;
+        }
+        listening.then_some(listener)
+    }
+
+    /// Drops errors no probe waits for anymore, e.g. about traffic of other programs.
+    fn evict(&self, errors: &mut Errors, now: Instant) {
+        errors.retain(|_, reported| {
+            reported.retain(|e| now.duration_since(e.received) < self.timeout);
+            !reported.is_empty()
+        });
+    }
+
+    /// Takes the error reported for the echo request to `destination` with `sequence`.
+    /// `identifier` is `None` for datagram sockets, whose identifier the kernel replaces.
+    pub fn take(
+        &self,
+        destination: IpAddr,
+        identifier: Option<u16>,
+        sequence: u16,
+    ) -> Option<FailureReason> {
+        let mut errors = self.errors.lock().ok()?;
+        let reported = errors.get_mut(&(destination, sequence))?;
+        let i = reported
+            .iter()
+            .position(|e| identifier.is_none_or(|id| id == e.identifier))?;
+        let error = reported.remove(i);
+        if reported.is_empty() {
+            errors.remove(&(destination, sequence));
+        }
+        Some(error.reason)
+    }
+}
+
+/// Short machine readable name of a reason, as serialized in `failures.json`.
+pub fn reason_name(reason: &FailureReason) -> &'static str {
+    match reason {
+        FailureReason::Timeout => "timeout",
+        FailureReason::Unreachable { .. } => "unreachable",
+        FailureReason::AdminProhibited { .. } => "admin_prohibited",
+        FailureReason::TtlExceeded => "ttl_exceeded",
+        FailureReason::SocketError { .. } => "socket_error",
+    }
+}
+
+fn label(reason: &FailureReason) -> String {
+    match reason {
+        FailureReason::Timeout => "timeout".to_string(),
+        FailureReason::Unreachable { code: Some(code) } => format!("unreachable (code {code})"),
+        FailureReason::Unreachable { code: None } => "unreachable".to_string(),
+        FailureReason::AdminProhibited { code } => format!("admin prohibited (code {code})"),
+        FailureReason::TtlExceeded => "ttl exceeded".to_string(),
+        FailureReason::SocketError { .. } => "socket error".to_string(),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FailureSummary {
+    reasons: BTreeMap<String, usize>,
+    /// Country -> reason -> number of targets.
+    countries: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+pub fn summarize_failures() -> anyhow::Result<()> {
+    let input = File::open("./failures.json")?;
+    let records: Vec<RecordWithFailure> = serde_json::from_reader(BufReader::new(input))?;
+
+    let mut reasons: BTreeMap<String, usize> = BTreeMap::new();
+    let mut countries: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+    for r in &records {
+        let reason = label(&r.reason);
+        *reasons.entry(reason.clone()).or_default() += 1;
+        let country = if r.country.is_empty() {
+            "unknown".to_string()
+        } else {
+            r.country.clone()
+        };
+        *countries
+            .entry(country)
+            .or_default()
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    println!("{} failed targets", records.len());
+    for (reason, n) in &reasons {
+        println!("  {reason}: {n}");
+    }
+    println!();
+    for (country, reasons) in &countries {
+        let reasons = reasons
+            .iter()
+            .map(|(reason, n)| format!("{reason}: {n}"))
+            .collect::<Vec<_>>();
+        println!("{country}: {}", reasons.join(", "));
+    }
+
+    let output = File::create("./failures_summary.json")?;
+    serde_json::to_writer_pretty(output, &FailureSummary { reasons, countries })?;
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::{fs::File, io::BufReader};
+
+use crate::config::env_or;
+use crate::distances::distances_file;
+use crate::structs::RecordWithDistance;
+
+/// Light in fiber covers ~200 km per ms one way, i.e. ~100 km per ms of round trip time.
+pub const FIBER_KM_PER_RTT_MS: f64 = 100.;
+
+/// Upper bound on the distance to a host given a round trip time in seconds.
+pub fn max_distance_km(rtt: f64) -> f64 {
+    rtt * 1000f64 * FIBER_KM_PER_RTT_MS
+}
+
+pub fn is_feasible(record: &RecordWithDistance) -> bool {
+    record.distance <= max_distance_km(record.time)
+}
+
+/// Whether infeasible records should be dropped before fitting and plotting.
+pub fn exclude_infeasible() -> anyhow::Result<bool> {
+    env_or("EXCLUDE_INFEASIBLE", false)
+}
+
+/// Whether records of anycast targets should be dropped instead of plotted separately.
+pub fn exclude_anycast() -> anyhow::Result<bool> {
+    env_or("EXCLUDE_ANYCAST", false)
+}
+
+#[derive(Debug, serde::Serialize)]
+struct InfeasibleRecord {
+    ip: String,
+    location: String,
+    time: f64,
+    distance: f64,
+    max_distance: f64,
+    /// How many km the geolocated distance exceeds the speed-of-light bound.
+    excess: f64,
+    /// Expected to break the bound, a nearer instance answered.
+    anycast: bool,
+}
+
+pub fn check_feasibility() -> anyhow::Result<()> {
+    let input = File::open(distances_file()?)?;
+    let records: Vec<RecordWithDistance> = serde_json::from_reader(BufReader::new(input))?;
+
+    let mut results = records
+        .iter()
+        .filter(|r| !is_feasible(r))
+        .map(|r| {
+            let max_distance = max_distance_km(r.time);
+            InfeasibleRecord {
+                ip: r.ip.clone(),
+                location: r.location.clone(),
+                time: r.time,
+                distance: r.distance,
+                max_distance,
+                excess: r.distance - max_distance,
+                anycast: r.anycast,
+            }
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.excess.total_cmp(&a.excess));
+
+    for r in &results {
+        println!(
+            "{} ({}): {:.1} ms allows {:.0} km, geolocated {:.0} km ({:.0} km over){}",
+            r.ip,
+            r.location,
+            r.time * 1000f64,
+            r.max_distance,
+            r.distance,
+            r.excess,
+            if r.anycast { ", anycast" } else { "" }
+        );
+    }
+    let anycast = results.iter().filter(|r| r.anycast).count();
+    println!(
+        "{}/{} records are physically impossible, {} of them anycast",
+        results.len(),
+        records.len(),
+        anycast
+    );
+
+    let output = File::create("./infeasible.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
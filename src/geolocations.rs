@@ -1,13 +1,94 @@
-use std::{env, fs::File, io::BufReader};
+use std::{collections::HashMap, env, fs::File, io::BufReader, net::IpAddr};
 
 use ipinfo::{IpInfo, IpInfoConfig};
+use maxminddb::geoip2;
 
-use crate::structs::{RecordWithIp, RecordWithGeolocation};
+use crate::config::{env_list, env_or};
+use crate::distances::distance_km;
+use crate::structs::{ProviderLocation, RecordWithGeolocation, RecordWithIp};
 
 pub async fn collect_geolocations() -> anyhow::Result<()> {
     let input = File::open("./with_ips.json")?;
     let records: Vec<RecordWithIp> = serde_json::from_reader(BufReader::new(input))?;
 
+    let ips = records
+        .iter()
+        .map(|r: &RecordWithIp| r.ip.clone())
+        .collect::<Vec<_>>();
+
+    let results = geolocate(ips).await?;
+
+    let output = File::create("./with_geolocations.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+/// Looks up `ips` with every provider in `GEO_PROVIDERS` and merges the answers.
+pub async fn geolocate(ips: Vec<String>) -> anyhow::Result<Vec<RecordWithGeolocation>> {
+    let mut lookups: HashMap<String, Vec<ProviderLocation>> = HashMap::new();
+    for provider in env_list("GEO_PROVIDERS", "ipinfo") {
+        let found = match provider.as_str() {
+            "ipinfo" => lookup_ipinfo(&ips).await?,
+            "mmdb" => lookup_mmdb(&ips, &env::var("GEO_MMDB")?)?,
+            "csv" => lookup_csv(&ips, &env::var("GEO_CSV")?)?,
+            other => anyhow::bail!("unknown geolocation provider: {other}"),
+        };
+        println!("{provider}: located {}/{} ips", found.len(), ips.len());
+        for l in found {
+            lookups.entry(l.0).or_default().push(l.1);
+        }
+    }
+
+    let mut results = Vec::new();
+    for ip in ips {
+        if let Some(providers) = lookups.remove(&ip) {
+            results.push(consensus(ip, providers));
+        }
+    }
+
+    Ok(results)
+}
+
+/// Picks the provider location closest to all others (the medoid) and records the
+/// largest pairwise distance between providers as the spread. Providers without
+/// coordinates are only picked if none has them. Ties go to the provider listed first in
+/// `GEO_PROVIDERS`, so with two providers that is always the first.
+fn consensus(ip: String, providers: Vec<ProviderLocation>) -> RecordWithGeolocation {
+    let point = |p: &ProviderLocation| Some((p.latitude?, p.longitude?));
+
+    let mut best = 0;
+    let mut best_sum = f64::MAX;
+    let mut spread = 0f64;
+    for (i, a) in providers.iter().enumerate() {
+        let Some(a) = point(a) else {
+            continue;
+        };
+        let mut sum = 0f64;
+        for b in providers.iter().filter_map(point) {
+            let d = distance_km(a, b);
+            sum += d;
+            spread = spread.max(d);
+        }
+        if sum < best_sum {
+            best = i;
+            best_sum = sum;
+        }
+    }
+
+    let chosen = &providers[best];
+    RecordWithGeolocation {
+        ip,
+        location: chosen.location.clone(),
+        country: chosen.country.clone(),
+        latitude: chosen.latitude,
+        longitude: chosen.longitude,
+        spread,
+        providers,
+    }
+}
+
+async fn lookup_ipinfo(ips: &[String]) -> anyhow::Result<Vec<(String, ProviderLocation)>> {
     let config = IpInfoConfig {
         token: Some(env::var("IPINFO")?.to_string()),
         ..Default::default()
@@ -15,10 +96,6 @@ pub async fn collect_geolocations() -> anyhow::Result<()> {
 
     let mut ipinfo = IpInfo::new(config).expect("should construct");
 
-    let ips = records
-        .iter()
-        .map(|r: &RecordWithIp| r.ip.clone())
-        .collect::<Vec<_>>();
     let ips = ips.iter().map(|i| i as &str).collect::<Vec<_>>();
     let ips = ips.chunks(500).collect::<Vec<_>>();
 
@@ -29,13 +106,27 @@ pub async fn collect_geolocations() -> anyhow::Result<()> {
         let res = ipinfo.lookup_batch(ips, Default::default()).await;
         match res {
             Ok(res) => {
-                let res = res
-                    .values()
-                    .map(|d| RecordWithGeolocation {
-                        ip: d.ip.clone(),
-                        location: d.city.clone(),
-                    })
-                    .collect::<Vec<_>>();
+                let res = res.values().map(|d| {
+                    let loc = d.loc.split_once(',').and_then(|(latitude, longitude)| {
+                        Some((
+                            latitude.trim().parse().ok()?,
+                            longitude.trim().parse().ok()?,
+                        ))
+                    });
+                    if loc.is_none() {
+                        println!("{}: unparsable loc {:?}, placed by city", d.ip, d.loc);
+                    }
+                    (
+                        d.ip.clone(),
+                        ProviderLocation {
+                            provider: "ipinfo".to_string(),
+                            location: d.city.clone(),
+                            country: d.country.clone(),
+                            latitude: loc.map(|l: (f64, f64)| l.0),
+                            longitude: loc.map(|l| l.1),
+                        },
+                    )
+                });
                 results.extend(res);
             }
             Err(e) => {
@@ -44,8 +135,233 @@ pub async fn collect_geolocations() -> anyhow::Result<()> {
         }
     }
 
-    let output = File::create("./with_geolocations.json")?;
+    Ok(results)
+}
+
+fn lookup_mmdb(ips: &[String], path: &str) -> anyhow::Result<Vec<(String, ProviderLocation)>> {
+    let reader = maxminddb::Reader::open_readfile(path)?;
+
+    let results = ips
+        .iter()
+        .filter_map(|ip| {
+            let city: geoip2::City = reader.lookup(ip.parse().ok()?).ok()?;
+            let location = city.location?;
+            Some((
+                ip.clone(),
+                ProviderLocation {
+                    provider: "mmdb".to_string(),
+                    location: city
+                        .city
+                        .and_then(|c| c.names)
+                        .and_then(|n| n.get("en").map(|s| s.to_string()))
+                        .unwrap_or_default(),
+                    country: city
+                        .country
+                        .and_then(|c| c.iso_code)
+                        .unwrap_or_default()
+                        .to_string(),
+                    latitude: Some(location.latitude?),
+                    longitude: Some(location.longitude?),
+                },
+            ))
+        })
+        .collect();
+
+    Ok(results)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct IpRange {
+    start: IpAddr,
+    end: IpAddr,
+    city: String,
+    country: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+/// Looks up ips in a csv file of `start,end,city,country,latitude,longitude` ranges.
+fn lookup_csv(ips: &[String], path: &str) -> anyhow::Result<Vec<(String, ProviderLocation)>> {
+    let input = File::open(path)?;
+    let mut rdr = csv::Reader::from_reader(BufReader::new(input));
+    let mut ranges = rdr
+        .deserialize()
+        .collect::<Result<Vec<IpRange>, csv::Error>>()?;
+    ranges.sort_by_key(|r| ip_key(r.start));
+
+    let results = ips
+        .iter()
+        .filter_map(|ip| {
+            let key = ip_key(ip.parse().ok()?);
+            // last range starting at or before the ip
+            let idx = ranges.partition_point(|r| ip_key(r.start) <= key);
+            let range = &ranges[idx.checked_sub(1)?];
+            if key > ip_key(range.end) {
+                return None;
+            }
+            Some((
+                ip.clone(),
+                ProviderLocation {
+                    provider: "csv".to_string(),
+                    location: range.city.clone(),
+                    country: range.country.clone(),
+                    latitude: Some(range.latitude),
+                    longitude: Some(range.longitude),
+                },
+            ))
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Maps both address families onto one ordered key space (v4 is mapped into v6).
+fn ip_key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+pub fn report_disagreements() -> anyhow::Result<()> {
+    let input = File::open("./with_geolocations.json")?;
+    let records: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+
+    let threshold: f64 = env_or("GEO_DISAGREEMENT_KM", 500.)?;
+
+    let mut results = records
+        .into_iter()
+        .filter(|r| r.spread > threshold)
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.spread.total_cmp(&a.spread));
+
+    for r in &results {
+        let providers = r
+            .providers
+            .iter()
+            .map(|p| format!("{}={} ({})", p.provider, p.location, p.country))
+            .collect::<Vec<_>>();
+        println!("{}: {:.0} km apart: {}", r.ip, r.spread, providers.join(", "));
+    }
+    println!(
+        "{} ips with providers more than {threshold} km apart",
+        results.len()
+    );
+
+    let output = File::create("./disagreements.json")?;
     serde_json::to_writer_pretty(output, &results)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Coordinates of a geolocated record, falling back to the city list for older files
+/// that only have a city name.
+pub fn coordinates(record: &RecordWithGeolocation) -> Option<(f64, f64)> {
+    if let (Some(latitude), Some(longitude)) = (record.latitude, record.longitude) {
+        return Some((latitude, longitude));
+    }
+    cities::all()
+        .iter()
+        .find(|c| c.city == record.location)
+        .map(|c| (c.latitude, c.longitude))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(name: &str, location: &str, coordinates: Option<(f64, f64)>) -> ProviderLocation {
+        ProviderLocation {
+            provider: name.to_string(),
+            location: location.to_string(),
+            country: "DE".to_string(),
+            latitude: coordinates.map(|c| c.0),
+            longitude: coordinates.map(|c| c.1),
+        }
+    }
+
+    #[test]
+    fn consensus_picks_the_medoid() {
+        let frankfurt = (50.11, 8.68);
+        let mainz = (50.00, 8.27);
+        let berlin = (52.52, 13.40);
+        let providers = vec![
+            provider("ipinfo", "Berlin", Some(berlin)),
+            provider("mmdb", "Frankfurt", Some(frankfurt)),
+            provider("csv", "Mainz", Some(mainz)),
+            provider("ipinfo", "Nowhere", None),
+        ];
+        let record = consensus("192.0.2.1".to_string(), providers);
+        assert_eq!(record.location, "Frankfurt");
+        assert_eq!(
+            (record.latitude, record.longitude),
+            (Some(50.11), Some(8.68))
+        );
+        assert_eq!(record.spread, distance_km(mainz, berlin));
+        assert_eq!(record.providers.len(), 4);
+
+        // two providers are equally close to each other
+        let providers = vec![
+            provider("mmdb", "Berlin", Some(berlin)),
+            provider("csv", "Frankfurt", Some(frankfurt)),
+        ];
+        let record = consensus("192.0.2.1".to_string(), providers);
+        assert_eq!(record.location, "Berlin");
+        assert_eq!(record.spread, distance_km(berlin, frankfurt));
+    }
+
+    #[test]
+    fn consensus_without_coordinates() {
+        let providers = vec![
+            provider("ipinfo", "Cologne", None),
+            provider("csv", "Bonn", None),
+        ];
+        let record = consensus("192.0.2.1".to_string(), providers);
+        assert_eq!(record.location, "Cologne");
+        assert_eq!((record.latitude, record.longitude), (None, None));
+        assert_eq!(record.spread, 0.);
+    }
+
+    #[test]
+    fn csv_ranges_of_both_families() {
+        let path = env::temp_dir().join(format!("pinger-ranges-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "start,end,city,country,latitude,longitude
+2001:db8::,2001:db8::ffff,Berlin,DE,52.52,13.40
+192.0.2.0,192.0.2.127,Frankfurt,DE,50.11,8.68
+192.0.2.128,192.0.2.255,Cologne,DE,50.94,6.96
+",
+        )
+        .unwrap();
+        let ips = [
+            "192.0.2.0",
+            "192.0.2.127",
+            "192.0.2.200",
+            "2001:db8::1",
+            "2001:db8::1:0",
+            "198.51.100.7",
+            "::ffff:192.0.2.1",
+            "not an ip",
+        ]
+        .map(String::from);
+        let found = lookup_csv(&ips, path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let found = found
+            .iter()
+            .map(|(ip, l)| (ip.as_str(), l.location.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                ("192.0.2.0", "Frankfurt"),
+                ("192.0.2.127", "Frankfurt"),
+                ("192.0.2.200", "Cologne"),
+                ("2001:db8::1", "Berlin"),
+                // v4 is mapped into v6, so both spellings find the same range
+                ("::ffff:192.0.2.1", "Frankfurt"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use std::{fs::File, io::BufReader};
+
+use anyhow::Context;
+use futures::{stream, StreamExt};
+use hyper::client::conn;
+use hyper::{header, Body, Request, Uri};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{self, TcpStream};
+use tokio::time;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::config::env_or;
+use crate::distances::vantage_point;
+use crate::structs::{
+    HttpTiming, ProbeMethod, RecordWithGeolocation, RecordWithIp, RecordWithTime,
+};
+
+/// Requests `url` from `ip` (the address that was geolocated) and times every phase.
+/// The host name is still resolved so the DNS time is part of the result.
+pub async fn probe_url(url: &str, ip: IpAddr, tls: &TlsConnector) -> anyhow::Result<HttpTiming> {
+    let uri: Uri = url.parse()?;
+    let https = uri.scheme_str() == Some("https");
+    let host = uri.host().context("url without host")?.to_string();
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let start = Instant::now();
+    net::lookup_host((host.as_str(), port))
+        .await?
+        .next()
+        .context("host did not resolve")?;
+    let dns = start.elapsed();
+
+    let stream = TcpStream::connect(SocketAddr::new(ip, port)).await?;
+    let connected = start.elapsed();
+
+    let (tls_time, response) = if https {
+        let stream = tls.connect(&host, stream).await?;
+        let handshaken = start.elapsed();
+        let h2 = stream.get_ref().negotiated_alpn()?.as_deref() == Some(b"h2");
+        let response = request(stream, h2, &uri, &host).await?;
+        (Some(handshaken - connected), response)
+    } else {
+        (None, request(stream, false, &uri, &host).await?)
+    };
+    let (status, version, ttfb) = response;
+
+    Ok(HttpTiming {
+        url: url.to_string(),
+        dns: dns.as_secs_f64(),
+        connect: (connected - dns).as_secs_f64(),
+        tls: tls_time.map(|t| t.as_secs_f64()),
+        ttfb: ttfb.as_secs_f64(),
+        total: start.elapsed().as_secs_f64(),
+        status,
+        protocol: format!("{:?}", version),
+    })
+}
+
+/// Sends a GET over an established connection, returns the status, version and the time
+/// to the response head. The body is read to the end before returning.
+async fn request<S>(
+    stream: S,
+    h2: bool,
+    uri: &Uri,
+    host: &str,
+) -> anyhow::Result<(u16, hyper::Version, Duration)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::Builder::new()
+        .http2_only(h2)
+        .handshake(stream)
+        .await?;
+    tokio::spawn(connection);
+
+    let target = if h2 {
+        uri.to_string()
+    } else {
+        uri.path_and_query()
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| "/".to_string())
+    };
+    let request = Request::get(target)
+        .header(header::HOST, host)
+        .header(header::USER_AGENT, "pinger")
+        .body(Body::empty())?;
+
+    let sent = Instant::now();
+    let response = sender.send_request(request).await?;
+    let ttfb = sent.elapsed();
+    let status = response.status().as_u16();
+    let version = response.version();
+    hyper::body::to_bytes(response.into_body()).await?;
+
+    Ok((status, version, ttfb))
+}
+
+pub fn tls_connector(insecure: bool) -> anyhow::Result<TlsConnector> {
+    let connector = native_tls::TlsConnector::builder()
+        .request_alpns(&["h2", "http/1.1"])
+        .danger_accept_invalid_certs(insecure)
+        .build()?;
+    Ok(TlsConnector::from(connector))
+}
+
+pub async fn probe_http() -> anyhow::Result<()> {
+    let input = File::open("./with_ips.json")?;
+    let records: Vec<RecordWithIp> = serde_json::from_reader(BufReader::new(input))?;
+    let input = File::open("./with_geolocations.json")?;
+    let geolocations: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+    let locations = geolocations
+        .into_iter()
+        .map(|g| (g.ip, g.location))
+        .collect::<HashMap<_, _>>();
+
+    // the phase used as `time`, so the distance analysis can run on http results
+    let phase: fn(&HttpTiming) -> f64 = match env_or("HTTP_TIME", "ttfb".to_string())?.as_str() {
+        "connect" => |t| t.connect,
+        "tls" => |t| t.tls.unwrap_or(t.connect),
+        "ttfb" => |t| t.ttfb,
+        "total" => |t| t.total,
+        other => anyhow::bail!("unknown HTTP_TIME: {other}"),
+    };
+    let timeout = Duration::from_secs(env_or("HTTP_TIMEOUT_S", 10)?);
+    let concurrency: usize = env_or("HTTP_CONCURRENCY", 32)?;
+    let tls = tls_connector(env_or("HTTP_INSECURE", false)?)?;
+    let origin = vantage_point()?;
+
+    let tasks = records.iter().filter_map(|r| {
+        let location = locations.get(&r.ip)?.clone();
+        let ip: IpAddr = r.ip.parse().ok()?;
+        let tls = &tls;
+        let origin = &origin;
+        Some(async move {
+            match time::timeout(timeout, probe_url(&r.url, ip, tls)).await {
+                Ok(Ok(timing)) => {
+                    println!("[+] {} {} done.", r.url, timing.status);
+                    Some(RecordWithTime {
+                        ip: r.ip.clone(),
+                        location,
+                        time: phase(&timing),
+                        stats: None,
+                        method: ProbeMethod::Http,
+                        http: Some(timing),
+                        origin: Some(origin.clone()),
+                    })
+                }
+                Ok(Err(e)) => {
+                    println!("Err: {} http {}", r.url, e);
+                    None
+                }
+                Err(_) => {
+                    println!("Err: {} http timeout", r.url);
+                    None
+                }
+            }
+        })
+    });
+
+    let results = stream::iter(tasks)
+        .buffer_unordered(concurrency)
+        .filter_map(|r| async { r })
+        .collect::<Vec<_>>()
+        .await;
+
+    let output = File::create("./with_http_times.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::Ipv4Addr;
+
+    use hyper::server::conn::Http;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+    use tokio::net::TcpListener;
+    use tokio_native_tls::TlsAcceptor;
+
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    /// How long the server waits before it answers.
+    const DELAY: Duration = Duration::from_millis(50);
+
+    async fn teapot(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        assert_eq!(request.uri().path(), "/brew");
+        assert_eq!(request.headers()[header::HOST], "localhost");
+        time::sleep(DELAY).await;
+        let response = Response::builder()
+            .status(StatusCode::IM_A_TEAPOT)
+            .body(Body::from("short and stout"))
+            .unwrap();
+        Ok(response)
+    }
+
+    async fn plain_server() -> SocketAddr {
+        let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(teapot)) });
+        let server = Server::try_bind(&SocketAddr::new(LOCALHOST, 0))
+            .unwrap()
+            .serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Self-signed certificate for `localhost`.
+    fn identity() -> native_tls::Identity {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        native_tls::Identity::from_pkcs8(
+            &cert.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn tls_server() -> SocketAddr {
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity()).unwrap());
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // clients that reject the certificate end the handshake
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    Http::new()
+                        .serve_connection(stream, service_fn(teapot))
+                        .await
+                        .ok();
+                });
+            }
+        });
+        addr
+    }
+
+    fn assert_phases(timing: &HttpTiming) {
+        assert_eq!(timing.status, 418);
+        assert_eq!(timing.protocol, "HTTP/1.1");
+        assert!(timing.dns > 0.);
+        assert!(timing.connect > 0.);
+        assert!(timing.ttfb >= DELAY.as_secs_f64());
+        let phases = timing.dns + timing.connect + timing.tls.unwrap_or(0.) + timing.ttfb;
+        assert!(timing.total >= phases, "{timing:?}");
+    }
+
+    #[tokio::test]
+    async fn plain() {
+        let addr = plain_server().await;
+        let url = format!("http://localhost:{}/brew", addr.port());
+        let timing = probe_url(&url, LOCALHOST, &tls_connector(false).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(timing.url, url);
+        assert_eq!(timing.tls, None);
+        assert_phases(&timing);
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let addr = tls_server().await;
+        let url = format!("https://localhost:{}/brew", addr.port());
+        let timing = probe_url(&url, LOCALHOST, &tls_connector(true).unwrap())
+            .await
+            .unwrap();
+        assert!(timing.tls.is_some_and(|t| t > 0.));
+        assert_phases(&timing);
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let addr = tls_server().await;
+        let url = format!("https://localhost:{}/brew", addr.port());
+        let result = probe_url(&url, LOCALHOST, &tls_connector(false).unwrap()).await;
+        assert!(result.is_err());
+    }
+}
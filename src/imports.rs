@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::IpAddr;
+
+use anyhow::Context;
+
+use crate::distances::{times_file, vantage_point};
+use crate::ping::rtt_stats;
+use crate::structs::{ProbeMethod, RecordWithGeolocation, RecordWithTime, RttStats};
+
+/// Path of the file to import, given after the stage name.
+pub fn input_path(stage: &str) -> anyhow::Result<String> {
+    std::env::args()
+        .nth(2)
+        .with_context(|| format!("usage: pinger {stage} <file>"))
+}
+
+/// Locations of the known targets by IP, so imported records can be placed.
+pub fn known_locations() -> anyhow::Result<HashMap<String, String>> {
+    let Ok(input) = File::open("./with_geolocations.json") else {
+        return Ok(HashMap::new());
+    };
+    let records: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+    Ok(records.into_iter().map(|r| (r.ip, r.location)).collect())
+}
+
+/// First address in `text`, also inside parentheses or followed by a colon.
+fn find_ip(text: &str) -> Option<IpAddr> {
+    text.split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ','))
+        .find_map(|token| {
+            token
+                .parse()
+                .or_else(|_| token.strip_suffix(':').unwrap_or(token).parse())
+                .ok()
+        })
+}
+
+/// What a tool reports about a run instead of the single RTTs, times in ms.
+#[derive(Debug, Clone, Copy)]
+struct Summary {
+    min: f64,
+    avg: f64,
+    max: f64,
+    stddev: f64,
+    /// Percentage of probes without a reply.
+    loss: f64,
+}
+
+/// Builds a record from the RTTs in ms of all answered probes, or from the summary of
+/// tools that don't print them.
+fn record(
+    ip: IpAddr,
+    samples_ms: Vec<f64>,
+    sent: usize,
+    summary: Option<Summary>,
+    locations: &HashMap<String, String>,
+) -> Option<RecordWithTime> {
+    let samples = samples_ms
+        .into_iter()
+        .map(|ms| ms / 1000.)
+        .collect::<Vec<_>>();
+    let stats = match (rtt_stats(samples, sent.max(1)), summary) {
+        (Some(stats), _) => stats,
+        (None, Some(s)) => RttStats {
+            min: s.min / 1000.,
+            avg: s.avg / 1000.,
+            median: None,
+            max: s.max / 1000.,
+            stddev: s.stddev / 1000.,
+            jitter: None,
+            loss: s.loss,
+            sent,
+            samples: Vec::new(),
+        },
+        (None, None) => return None,
+    };
+    let ip = ip.to_string();
+    Some(RecordWithTime {
+        location: locations.get(&ip).cloned().unwrap_or_default(),
+        ip,
+        time: stats.min,
+        stats: Some(stats),
+        method: ProbeMethod::Icmp,
+        http: None,
+        origin: None,
+    })
+}
+
+/// Parses the output of one or more runs of iputils or BSD `ping`.
+fn parse_ping(text: &str, locations: &HashMap<String, String>) -> Vec<RecordWithTime> {
+    struct Run {
+        ip: Option<IpAddr>,
+        samples: Vec<f64>,
+        sent: Option<usize>,
+        received: Option<usize>,
+        /// min, avg, max and mdev/stddev in ms.
+        summary: Option<[f64; 4]>,
+    }
+    let finish = |run: Run| {
+        let sent = run.sent.unwrap_or(run.samples.len());
+        // `ping -q` only prints the summary
+        let summary = match (run.summary, run.received) {
+            (Some([min, avg, max, stddev]), Some(received)) if sent > 0 => Some(Summary {
+                min,
+                avg,
+                max,
+                stddev,
+                loss: sent.saturating_sub(received) as f64 / sent as f64 * 100.,
+            }),
+            _ => None,
+        };
+        record(run.ip?, run.samples, sent, summary, locations)
+    };
+
+    let mut results = Vec::new();
+    let mut run: Option<Run> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix("PING ") {
+            results.extend(run.take().and_then(finish));
+            run = Some(Run {
+                ip: find_ip(header),
+                samples: Vec::new(),
+                sent: None,
+                received: None,
+                summary: None,
+            });
+            continue;
+        }
+        let Some(run) = run.as_mut() else {
+            continue;
+        };
+        if line.ends_with("(DUP!)") {
+            // a duplicate answers a probe that was already counted
+            continue;
+        }
+        if let Some((_, time)) = line.split_once("time=") {
+            if let Some(ms) = time.split_whitespace().next().and_then(|t| t.parse().ok()) {
+                run.samples.push(ms);
+            }
+        } else if line.contains("packets transmitted") {
+            // 3 packets transmitted, 3 received, +1 duplicates, 0% packet loss
+            let counts = line
+                .split(',')
+                .map(|part| part.split_whitespace().next()?.parse().ok())
+                .collect::<Vec<Option<usize>>>();
+            run.sent = counts.first().copied().flatten();
+            run.received = counts.get(1).copied().flatten();
+        } else if line.contains("min/avg/max") {
+            // rtt min/avg/max/mdev = 11.193/11.318/11.517/0.116 ms
+            run.summary = line.split_once('=').and_then(|(_, values)| {
+                let values = values
+                    .split_whitespace()
+                    .next()?
+                    .split('/')
+                    .map(|v| v.parse().ok())
+                    .collect::<Option<Vec<f64>>>()?;
+                values.try_into().ok()
+            });
+        }
+    }
+    results.extend(run.and_then(finish));
+    results
+}
+
+/// Parses `fping -C` output, `host : 11.20 11.31 - 11.52` with `-` for a lost probe.
+fn parse_fping(text: &str, locations: &HashMap<String, String>) -> Vec<RecordWithTime> {
+    text.lines()
+        .filter_map(|line| {
+            let (host, values) = line.split_once(" : ")?;
+            let values = values
+                .split_whitespace()
+                .map(|v| match v {
+                    "-" => Ok(None),
+                    v => v.parse::<f64>().map(Some),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?;
+            let Ok(ip) = host.trim().parse() else {
+                println!(
+                    "skipping {}, run fping with -A to print addresses",
+                    host.trim()
+                );
+                return None;
+            };
+            let sent = values.len();
+            let samples = values.into_iter().flatten().collect();
+            record(ip, samples, sent, None, locations)
+        })
+        .collect()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MtrJson {
+    report: MtrReport,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MtrReport {
+    mtr: MtrInfo,
+    hubs: Vec<MtrHub>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MtrInfo {
+    dst: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MtrHub {
+    host: String,
+    #[serde(rename = "Loss%")]
+    loss: f64,
+    #[serde(rename = "Snt")]
+    sent: f64,
+    #[serde(rename = "Avg")]
+    avg: f64,
+    #[serde(rename = "Best")]
+    best: f64,
+    #[serde(rename = "Wrst")]
+    worst: f64,
+    #[serde(rename = "StDev")]
+    stddev: f64,
+}
+
+/// Parses `mtr --json` or `mtr --report` output. mtr only reports a summary per hop, the
+/// last hop gives the target's minimum RTT.
+fn parse_mtr(
+    text: &str,
+    locations: &HashMap<String, String>,
+) -> anyhow::Result<Vec<RecordWithTime>> {
+    let mut destinations = Vec::new();
+    if text.trim_start().starts_with('{') {
+        let json: MtrJson = serde_json::from_str(text)?;
+        if let Some(hub) = json.report.hubs.last() {
+            let ip = find_ip(&hub.host).or_else(|| json.report.mtr.dst.parse().ok());
+            let summary = Summary {
+                min: hub.best,
+                avg: hub.avg,
+                max: hub.worst,
+                stddev: hub.stddev,
+                loss: hub.loss,
+            };
+            destinations.push((ip, hub.sent as usize, summary));
+        }
+    } else {
+        // HOST: name  Loss%  Snt  Last  Avg  Best  Wrst StDev, then one row per hop
+        let mut reports: Vec<(Vec<&str>, Option<&str>)> = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.starts_with("HOST:") {
+                reports.push((line.split_whitespace().skip(2).collect(), None));
+            } else if let (Some((_, row)), Some(report)) =
+                (line.split_once("|--"), reports.last_mut())
+            {
+                report.1 = Some(row);
+            }
+        }
+        for (columns, row) in reports {
+            let Some(row) = row else {
+                continue;
+            };
+            let fields = row.split_whitespace().collect::<Vec<_>>();
+            // the host may be "name (address)", so count the columns from the end
+            let Some(offset) = fields.len().checked_sub(columns.len()) else {
+                continue;
+            };
+            let value = |name| {
+                let i = columns.iter().position(|c| *c == name)?;
+                fields
+                    .get(offset + i)?
+                    .trim_end_matches('%')
+                    .parse::<f64>()
+                    .ok()
+            };
+            // custom --order fields are not supported
+            let summary = (|| {
+                Some(Summary {
+                    min: value("Best")?,
+                    avg: value("Avg")?,
+                    max: value("Wrst")?,
+                    stddev: value("StDev")?,
+                    loss: value("Loss%")?,
+                })
+            })();
+            if let (Some(summary), Some(sent)) = (summary, value("Snt")) {
+                destinations.push((find_ip(row), sent as usize, summary));
+            }
+        }
+    }
+
+    Ok(destinations
+        .into_iter()
+        .filter_map(|(ip, sent, summary)| {
+            if summary.loss >= 100. {
+                return None;
+            }
+            record(ip?, Vec::new(), sent, Some(summary), locations)
+        })
+        .collect())
+}
+
+pub fn import_tool_output(tool: &str) -> anyhow::Result<()> {
+    let path = input_path(&format!("import-{tool}"))?;
+    let text = fs::read_to_string(&path)?;
+    let locations = known_locations()?;
+
+    let mut results = match tool {
+        "ping" => parse_ping(&text, &locations),
+        "fping" => parse_fping(&text, &locations),
+        "mtr" => parse_mtr(&text, &locations)?,
+        other => anyhow::bail!("unknown tool: {other}"),
+    };
+    // the data was collected elsewhere, ORIGIN_* describe where
+    let origin = vantage_point()?;
+    for r in &mut results {
+        r.origin = Some(origin.clone());
+    }
+    println!(
+        "imported {} targets from {} measured at {}",
+        results.len(),
+        path,
+        origin.id
+    );
+
+    let output = File::create(times_file()?)?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn located() -> HashMap<String, String> {
+        HashMap::from([("192.0.2.1".to_string(), "Frankfurt".to_string())])
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn assert_samples_ms(stats: &RttStats, ms: &[f64]) {
+        assert_eq!(stats.samples.len(), ms.len(), "{:?}", stats.samples);
+        for (s, ms) in stats.samples.iter().zip(ms) {
+            assert!(close(*s, ms / 1000.), "{:?}", stats.samples);
+        }
+    }
+
+    #[test]
+    fn ping_skips_duplicates() {
+        let text = "\
+PING 192.0.2.1 (192.0.2.1) 56(84) bytes of data.
+64 bytes from 192.0.2.1: icmp_seq=1 ttl=57 time=11.2 ms
+64 bytes from 192.0.2.1: icmp_seq=1 ttl=57 time=9.0 ms (DUP!)
+64 bytes from 192.0.2.1: icmp_seq=3 ttl=57 time=11.6 ms
+
+--- 192.0.2.1 ping statistics ---
+3 packets transmitted, 2 received, +1 duplicates, 33.3333% packet loss, time 2003ms
+rtt min/avg/max/mdev = 9.000/10.600/11.600/1.114 ms
+";
+        let records = parse_ping(text, &located());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ip, "192.0.2.1");
+        assert_eq!(records[0].location, "Frankfurt");
+        let stats = records[0].stats.as_ref().unwrap();
+        assert_samples_ms(stats, &[11.2, 11.6]);
+        assert!(close(records[0].time, 0.0112));
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.lost(), 1);
+    }
+
+    #[test]
+    fn ping_quiet_summary() {
+        let text = "\
+PING example.com (198.51.100.7) 56(84) bytes of data.
+
+--- example.com ping statistics ---
+5 packets transmitted, 4 received, 20% packet loss, time 4005ms
+rtt min/avg/max/mdev = 11.193/11.318/11.517/0.116 ms
+PING 192.0.2.9 (192.0.2.9) 56(84) bytes of data.
+
+--- 192.0.2.9 ping statistics ---
+2 packets transmitted, 0 received, 100% packet loss, time 1001ms
+";
+        let records = parse_ping(text, &located());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ip, "198.51.100.7");
+        assert_eq!(records[0].location, "");
+        let stats = records[0].stats.as_ref().unwrap();
+        assert!(close(stats.min, 0.011193));
+        assert!(close(stats.avg, 0.011318));
+        assert!(close(stats.max, 0.011517));
+        assert!(close(stats.stddev, 0.000116));
+        assert_eq!((stats.sent, stats.loss), (5, 20.));
+        assert_eq!((stats.median, stats.jitter), (None, None));
+        assert!(stats.samples.is_empty());
+    }
+
+    #[test]
+    fn fping_lost_probes() {
+        let text = "\
+192.0.2.1 : 11.20 11.31 - 11.52
+192.0.2.2 : - - - -
+example.com : 1.00 1.10 1.20 1.30
+";
+        let records = parse_fping(text, &located());
+        assert_eq!(records.len(), 1);
+        let stats = records[0].stats.as_ref().unwrap();
+        assert_samples_ms(stats, &[11.2, 11.31, 11.52]);
+        assert_eq!((stats.sent, stats.lost()), (4, 1));
+        assert!(close(records[0].time, 0.0112));
+    }
+
+    #[test]
+    fn mtr_report_last_hop() {
+        let text = "\
+Start: 2023-10-20T10:00:00+0200
+HOST: box                         Loss%   Snt   Last   Avg  Best  Wrst StDev
+  1.|-- 10.0.0.1                   0.0%    10    0.5   0.6   0.4   0.9   0.1
+  2.|-- host.example (192.0.2.1)  10.0%    10   11.4  11.6  11.2  12.3   0.3
+";
+        let records = parse_mtr(text, &located()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ip, "192.0.2.1");
+        assert_eq!(records[0].location, "Frankfurt");
+        let stats = records[0].stats.as_ref().unwrap();
+        assert!(close(stats.min, 0.0112));
+        assert!(close(stats.avg, 0.0116));
+        assert!(close(stats.max, 0.0123));
+        assert!(close(stats.stddev, 0.0003));
+        assert_eq!((stats.sent, stats.loss), (10, 10.));
+    }
+}
@@ -12,6 +12,12 @@ pub fn collect_ips() -> anyhow::Result<()> {
         .deserialize()
         .map(|r: Result<Record, csv::Error>| r.unwrap())
         .collect::<Vec<_>>();
+    let countries = countries(&results);
+    let unknown = countries.iter().filter(|c| c.is_empty()).count();
+    println!(
+        "{unknown}/{} universities without a country, the locality report leaves them out",
+        results.len()
+    );
 
     let ips = results
         .par_iter()
@@ -29,6 +35,7 @@ pub fn collect_ips() -> anyhow::Result<()> {
                     ip: ips[0].to_string(),
                     name: r.name.clone(),
                     url: r.url.clone(),
+                    country: countries[i].clone(),
                 })
             } else {
                 None
@@ -43,3 +50,61 @@ pub fn collect_ips() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Country of every university: the `country` column if the list has one, else the
+/// url's ccTLD. The list is sorted by country, so a university with a generic TLD gets
+/// the country of the closest ccTLDs before and after it if both agree.
+fn countries(records: &[Record]) -> Vec<String> {
+    let own = records
+        .iter()
+        .map(|r| {
+            if r.country.is_empty() {
+                country_from_url(&r.url)
+            } else {
+                r.country.to_uppercase()
+            }
+        })
+        .collect::<Vec<_>>();
+    let closest = |countries: &mut dyn Iterator<Item = &String>| {
+        let mut last = String::new();
+        countries
+            .map(|c| {
+                if !c.is_empty() {
+                    last = c.clone();
+                }
+                last.clone()
+            })
+            .collect::<Vec<_>>()
+    };
+    let before = closest(&mut own.iter());
+    let mut after = closest(&mut own.iter().rev());
+    after.reverse();
+
+    own.into_iter()
+        .zip(before.into_iter().zip(after))
+        .map(|(own, (before, after))| {
+            if !own.is_empty() || before != after {
+                own
+            } else {
+                before
+            }
+        })
+        .collect()
+}
+
+/// Guesses the country from the url's ccTLD, returns an empty string for generic TLDs.
+pub fn country_from_url(url: &str) -> String {
+    let host = url
+        .trim_start_matches("http://")
+        .trim_start_matches("https://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host.rsplit('.').next() {
+        Some("uk") => "GB".to_string(),
+        Some(tld) if tld.len() == 2 && tld.chars().all(|c| c.is_ascii_alphabetic()) => {
+            tld.to_uppercase()
+        }
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+};
+
+use crate::ips::country_from_url;
+use crate::structs::{RecordWithGeolocation, RecordWithIp};
+
+#[derive(Debug, serde::Serialize)]
+struct CountryLocality {
+    country: String,
+    universities: usize,
+    hosted_abroad: usize,
+    share_abroad: f64,
+    /// Server country -> number of universities hosted there.
+    destinations: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Flow {
+    from: String,
+    to: String,
+    universities: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct HostingLocality {
+    countries: Vec<CountryLocality>,
+    flows: Vec<Flow>,
+    /// Universities left out because their own country is not known.
+    unknown_country: usize,
+    /// Universities left out because their server was not geolocated.
+    unknown_server: usize,
+}
+
+pub fn report_hosting_locality() -> anyhow::Result<()> {
+    let input = File::open("./with_ips.json")?;
+    let universities: Vec<RecordWithIp> = serde_json::from_reader(BufReader::new(input))?;
+    let input = File::open("./with_geolocations.json")?;
+    let geolocations: Vec<RecordWithGeolocation> =
+        serde_json::from_reader(BufReader::new(input))?;
+
+    let servers = geolocations
+        .iter()
+        .filter(|g| !g.country.is_empty())
+        .map(|g| (g.ip.as_str(), g.country.as_str()))
+        .collect::<HashMap<_, _>>();
+
+    let mut destinations: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+    let (mut unknown_country, mut unknown_server) = (0, 0);
+    for u in &universities {
+        // older files were written before the country was filled in from the list
+        let from = if u.country.is_empty() {
+            country_from_url(&u.url)
+        } else {
+            u.country.clone()
+        };
+        match servers.get(u.ip.as_str()) {
+            _ if from.is_empty() => unknown_country += 1,
+            Some(to) => {
+                *destinations
+                    .entry(from)
+                    .or_default()
+                    .entry(to.to_string())
+                    .or_default() += 1;
+            }
+            None => unknown_server += 1,
+        }
+    }
+
+    let mut countries = destinations
+        .into_iter()
+        .map(|(country, destinations)| {
+            let universities = destinations.values().sum::<usize>();
+            let hosted_abroad = destinations
+                .iter()
+                .filter(|(to, _)| **to != country)
+                .map(|(_, n)| n)
+                .sum::<usize>();
+            CountryLocality {
+                share_abroad: hosted_abroad as f64 / universities as f64,
+                country,
+                universities,
+                hosted_abroad,
+                destinations,
+            }
+        })
+        .collect::<Vec<_>>();
+    countries.sort_by(|a, b| b.share_abroad.total_cmp(&a.share_abroad));
+
+    let mut flows = countries
+        .iter()
+        .flat_map(|c| {
+            c.destinations
+                .iter()
+                .filter(|(to, _)| **to != c.country)
+                .map(|(to, n)| Flow {
+                    from: c.country.clone(),
+                    to: to.clone(),
+                    universities: *n,
+                })
+        })
+        .collect::<Vec<_>>();
+    flows.sort_by_key(|f| std::cmp::Reverse(f.universities));
+
+    println!("country  universities  abroad  share  top destinations");
+    for c in &countries {
+        let mut top = c
+            .destinations
+            .iter()
+            .filter(|(to, _)| **to != c.country)
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.cmp(a.1));
+        let top = top
+            .iter()
+            .take(3)
+            .map(|(to, n)| format!("{to} ({n})"))
+            .collect::<Vec<_>>();
+        println!(
+            "{:<7}  {:>12}  {:>6}  {:>4.0}%  {}",
+            c.country,
+            c.universities,
+            c.hosted_abroad,
+            c.share_abroad * 100f64,
+            top.join(", ")
+        );
+    }
+    println!();
+    println!("top flows:");
+    for f in flows.iter().take(20) {
+        println!("{} -> {}: {}", f.from, f.to, f.universities);
+    }
+    println!(
+        "left out: {unknown_country} universities without a country, {unknown_server} without a geolocated server"
+    );
+
+    let output = File::create("./hosting_locality.json")?;
+    let locality = HostingLocality {
+        countries,
+        flows,
+        unknown_country,
+        unknown_server,
+    };
+    serde_json::to_writer_pretty(output, &locality)?;
+
+    Ok(())
+}
@@ -1,27 +1,97 @@
+use std::env;
+
 use dotenv::dotenv;
 
+use agent::run_agent;
+use anycast::detect_anycast;
+use assertions::assert_slos;
+use atlas::import_atlas;
+use cbg::estimate_locations;
+use collector::collect;
 use distances::calculate_distances;
-use geolocations::collect_geolocations;
+use failures::summarize_failures;
+use feasibility::check_feasibility;
+use geolocations::{collect_geolocations, report_disagreements};
+use http::probe_http;
+use imports::import_tool_output;
 use ips::collect_ips;
+use locality::report_hosting_locality;
+use merge::merge_origins;
+use monitor::monitor;
+use path_distances::calculate_path_distances;
+use pcap::import_pcap;
 use ping::ping_ips;
 use plotting::plot_data;
+use probers::report_probers;
+use self_location::locate_self;
+use sweep::sweep_sizes;
+use traceroute::trace_ips;
 
+mod agent;
+mod alerts;
+mod anycast;
+mod atlas;
+mod assertions;
+mod cbg;
+mod collector;
+mod config;
 mod distances;
+mod failures;
+mod feasibility;
 mod geolocations;
+mod http;
+mod imports;
 mod ips;
+mod locality;
+mod merge;
+mod metrics;
+mod monitor;
+mod path_distances;
+mod pcap;
 mod ping;
 mod plotting;
+mod probers;
+mod scheduler;
+mod self_location;
 mod structs;
+mod sweep;
+mod tcp;
+mod traceroute;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv()?;
-    // collect_ips()?;
-    // collect_geolocations().await?;
-    // ping_ips().await?;
-    // calculate_distances().await?;
 
-    plot_data()?;
+    match env::args().nth(1).as_deref() {
+        Some("ips") => collect_ips()?,
+        Some("geolocations") => collect_geolocations().await?,
+        Some("disagreements") => report_disagreements()?,
+        Some("locality") => report_hosting_locality()?,
+        Some("probers") => report_probers()?,
+        Some("ping") => ping_ips().await?,
+        Some("size-sweep") => sweep_sizes().await?,
+        Some("traceroute") => trace_ips().await?,
+        Some("path-distances") => calculate_path_distances().await?,
+        Some("failures") => summarize_failures()?,
+        Some("assert") => assert_slos().await?,
+        Some("monitor") => monitor().await?,
+        Some("collector") => collect().await?,
+        Some("agent") => run_agent().await?,
+        Some("http") => probe_http().await?,
+        Some("import-pcap") => import_pcap()?,
+        Some("import-ping") => import_tool_output("ping")?,
+        Some("import-fping") => import_tool_output("fping")?,
+        Some("import-mtr") => import_tool_output("mtr")?,
+        Some("import-atlas") => import_atlas()?,
+        Some("merge") => merge_origins()?,
+        Some("anycast") => detect_anycast()?,
+        Some("distances") => calculate_distances().await?,
+        Some("feasibility") => check_feasibility()?,
+        Some("cbg") => estimate_locations()?,
+        Some("locate-self") => locate_self()?,
+        Some("plot") | None => plot_data()?,
+        Some(stage) => anyhow::bail!("unknown stage: {stage}"),
+    }
 
     Ok(())
 }
@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+use std::{fs::File, io::BufReader};
+
+use crate::cbg::load_origins;
+use crate::config::env_or;
+use crate::structs::{RecordWithTime, VantagePoint};
+
+/// Output of the merge stage and the collector, input of the CBG stage.
+pub fn merged_file() -> anyhow::Result<String> {
+    env_or("MERGED_FILE", "./with_times_merged.json".to_string())
+}
+
+/// Merges the times files of all `ORIGINS` into one dataset, every record tagged with the
+/// origin it was measured from, so the distance, plotting and CBG stages can run on it.
+pub fn merge_origins() -> anyhow::Result<()> {
+    let origins = load_origins(&env_or("ORIGINS", "./origins.json".to_string())?)?;
+    let output_file = merged_file()?;
+
+    let mut merged: BTreeMap<(String, String), RecordWithTime> = BTreeMap::new();
+    let mut conflicts = 0;
+    for origin in &origins {
+        let input = File::open(&origin.times)?;
+        let records: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+        println!("{}: {} records", origin.id, records.len());
+        for mut r in records {
+            let vantage = r.origin.get_or_insert_with(|| VantagePoint {
+                id: origin.id.clone(),
+                latitude: origin.latitude,
+                longitude: origin.longitude,
+            });
+            let key = (vantage.id.clone(), r.ip.clone());
+            // the same target measured twice from one origin, the lower RTT is closer to
+            // the propagation delay
+            if let Some(kept) = merged.get(&key) {
+                conflicts += 1;
+                let (kept_ms, new_ms) = (kept.time * 1000f64, r.time * 1000f64);
+                println!(
+                    "{} measured {} twice: {kept_ms:.1} ms and {new_ms:.1} ms, keeping the lower",
+                    key.0, key.1
+                );
+                if kept.time <= r.time {
+                    continue;
+                }
+            }
+            merged.insert(key, r);
+        }
+    }
+
+    let results = merged.into_values().collect::<Vec<_>>();
+    println!(
+        "{} records from {} origins in {}, {conflicts} duplicates resolved",
+        results.len(),
+        origins.len(),
+        output_file
+    );
+
+    let output = File::create(output_file)?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
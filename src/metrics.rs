@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+
+use crate::failures::reason_name;
+use crate::structs::{RecordWithIp, Sample};
+
+/// Upper bounds of the RTT histogram buckets in seconds.
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.15, 0.25, 0.5, 1., 2.5,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Labels {
+    target: String,
+    ip: String,
+    location: String,
+    country: String,
+    family: &'static str,
+}
+
+#[derive(Debug, Default)]
+struct TargetMetrics {
+    /// Non-cumulative counts per bucket, the last one is `+Inf`.
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+    sent: u64,
+    lost: u64,
+    /// Rounds in which the target did not answer, by reason.
+    errors: BTreeMap<&'static str, u64>,
+}
+
+/// Names of the targets by IP from `with_ips.json`, joined if several share an IP.
+pub fn target_names() -> anyhow::Result<HashMap<String, String>> {
+    let Ok(input) = File::open("./with_ips.json") else {
+        return Ok(HashMap::new());
+    };
+    let records: Vec<RecordWithIp> = serde_json::from_reader(BufReader::new(input))?;
+    let mut names: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for r in records {
+        names.entry(r.ip).or_default().insert(r.name);
+    }
+    Ok(names
+        .into_iter()
+        .map(|(ip, names)| (ip, names.into_iter().collect::<Vec<_>>().join(", ")))
+        .collect())
+}
+
+/// Per-target counters of a monitoring run, rendered in the Prometheus text format.
+pub struct Metrics {
+    /// Target names by IP, targets without one are labelled with their IP.
+    names: HashMap<String, String>,
+    targets: Mutex<BTreeMap<Labels, TargetMetrics>>,
+}
+
+impl Metrics {
+    pub fn new(names: HashMap<String, String>) -> Self {
+        Self {
+            names,
+            targets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a sample of a target that was sent `sent` probes.
+    pub fn observe(&self, sample: &Sample, sent: u16) {
+        let family = match sample.ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => "ipv6",
+            _ => "ipv4",
+        };
+        let labels = Labels {
+            target: self.names.get(&sample.ip).unwrap_or(&sample.ip).clone(),
+            ip: sample.ip.clone(),
+            location: sample.location.clone(),
+            country: sample.country.clone(),
+            family,
+        };
+
+        let mut targets = self.targets.lock().unwrap();
+        let metrics = targets.entry(labels).or_default();
+        match (&sample.stats, &sample.failure) {
+            (Some(stats), _) => {
+                for rtt in &stats.samples {
+                    let bucket = BUCKETS.iter().take_while(|b| rtt > b).count();
+                    metrics.buckets[bucket] += 1;
+                    metrics.sum += rtt;
+                    metrics.count += 1;
+                }
+                metrics.sent += stats.sent as u64;
+                metrics.lost += stats.lost() as u64;
+            }
+            (None, failure) => {
+                metrics.sent += u64::from(sent);
+                metrics.lost += u64::from(sent);
+                if let Some(failure) = failure {
+                    *metrics.errors.entry(reason_name(failure)).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let targets = self.targets.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP pinger_rtt_seconds Round trip time of answered probes.\n");
+        out.push_str("# TYPE pinger_rtt_seconds histogram\n");
+        for (labels, m) in targets.iter() {
+            let labels = format_labels(labels);
+            let mut cumulative = 0;
+            for (i, count) in m.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+                let _ = writeln!(
+                    out,
+                    "pinger_rtt_seconds_bucket{{{labels},le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(out, "pinger_rtt_seconds_sum{{{labels}}} {}", m.sum);
+            let _ = writeln!(out, "pinger_rtt_seconds_count{{{labels}}} {}", m.count);
+        }
+
+        out.push_str("# HELP pinger_probes_sent_total Probes sent.\n");
+        out.push_str("# TYPE pinger_probes_sent_total counter\n");
+        for (labels, m) in targets.iter() {
+            let labels = format_labels(labels);
+            let _ = writeln!(out, "pinger_probes_sent_total{{{labels}}} {}", m.sent);
+        }
+
+        out.push_str("# HELP pinger_probes_lost_total Probes without an answer.\n");
+        out.push_str("# TYPE pinger_probes_lost_total counter\n");
+        for (labels, m) in targets.iter() {
+            let labels = format_labels(labels);
+            let _ = writeln!(out, "pinger_probes_lost_total{{{labels}}} {}", m.lost);
+        }
+
+        out.push_str("# HELP pinger_probe_errors_total Rounds without any answer, by reason.\n");
+        out.push_str("# TYPE pinger_probe_errors_total counter\n");
+        for (labels, m) in targets.iter() {
+            let labels = format_labels(labels);
+            for (reason, n) in &m.errors {
+                let _ = writeln!(
+                    out,
+                    "pinger_probe_errors_total{{{labels},reason=\"{reason}\"}} {n}"
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &Labels) -> String {
+    format!(
+        "target=\"{}\",ip=\"{}\",location=\"{}\",country=\"{}\",family=\"{}\"",
+        escape(&labels.target),
+        escape(&labels.ip),
+        escape(&labels.location),
+        escape(&labels.country),
+        labels.family
+    )
+}
+
+async fn handle(
+    metrics: Arc<Metrics>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match request.uri().path() {
+        "/metrics" => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(Body::from(metrics.render())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found\n")),
+    };
+    Ok(response.expect("valid response"))
+}
+
+/// Serves `/metrics` on `addr` in the background, returns the address it is bound to.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<SocketAddr> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let service = service_fn(move |request| handle(metrics.clone(), request));
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    let addr = server.local_addr();
+    println!("serving metrics on http://{addr}/metrics");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            println!("Err: metrics server {}", e);
+        }
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use hyper::Client;
+
+    use super::*;
+    use crate::structs::{FailureReason, ProbeMethod, RttStats};
+
+    fn sample(ip: &str, samples: Vec<f64>, failure: Option<FailureReason>) -> Sample {
+        let stats = (!samples.is_empty()).then(|| RttStats {
+            min: samples[0],
+            avg: samples[0],
+            median: None,
+            max: samples[0],
+            stddev: 0.,
+            jitter: None,
+            loss: (4 - samples.len()) as f64 / 4. * 100.,
+            sent: 4,
+            samples,
+        });
+        Sample {
+            timestamp: 0,
+            ip: ip.to_string(),
+            location: "Cologne".to_string(),
+            country: "DE".to_string(),
+            method: ProbeMethod::Icmp,
+            stats,
+            failure,
+            origin: None,
+        }
+    }
+
+    async fn scrape(addr: SocketAddr, path: &str) -> (StatusCode, String) {
+        let uri = format!("http://{addr}{path}").parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn exposition() {
+        let names = HashMap::from([("10.0.0.1".to_string(), "Uni \"Köln\"".to_string())]);
+        let metrics = Arc::new(Metrics::new(names));
+        metrics.observe(&sample("10.0.0.1", vec![0.003, 0.02, 0.02], None), 4);
+        metrics.observe(
+            &sample("2001:db8::1", Vec::new(), Some(FailureReason::Timeout)),
+            4,
+        );
+        let addr = serve(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), metrics).unwrap();
+
+        let (status, text) = scrape(addr, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        let v4 =
+            r#"target="Uni \"Köln\"",ip="10.0.0.1",location="Cologne",country="DE",family="ipv4""#;
+        let v6 = r#"target="2001:db8::1",ip="2001:db8::1",location="Cologne",country="DE",family="ipv6""#;
+        for line in [
+            "# TYPE pinger_rtt_seconds histogram".to_string(),
+            format!("pinger_rtt_seconds_bucket{{{v4},le=\"0.0025\"}} 0"),
+            format!("pinger_rtt_seconds_bucket{{{v4},le=\"0.005\"}} 1"),
+            format!("pinger_rtt_seconds_bucket{{{v4},le=\"0.025\"}} 3"),
+            format!("pinger_rtt_seconds_bucket{{{v4},le=\"+Inf\"}} 3"),
+            format!("pinger_rtt_seconds_count{{{v4}}} 3"),
+            format!("pinger_rtt_seconds_count{{{v6}}} 0"),
+            format!("pinger_probes_sent_total{{{v4}}} 4"),
+            format!("pinger_probes_lost_total{{{v4}}} 1"),
+            format!("pinger_probes_sent_total{{{v6}}} 4"),
+            format!("pinger_probes_lost_total{{{v6}}} 4"),
+            format!("pinger_probe_errors_total{{{v6},reason=\"timeout\"}} 1"),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing in\n{text}");
+        }
+        assert!(!text.contains(&format!("pinger_probe_errors_total{{{v4}")));
+
+        let (status, _) = scrape(addr, "/").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
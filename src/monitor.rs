@@ -0,0 +1,259 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use tokio::task::JoinError;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::alerts::Alerts;
+use crate::config::{env_opt, env_or};
+use crate::metrics::{self, Metrics};
+use crate::ping::Pinger;
+use crate::structs::{RecordWithFailure, RecordWithGeolocation, RecordWithTime, Sample};
+
+#[derive(Debug, Clone)]
+struct MonitorConfig {
+    /// Time between the starts of two rounds over all targets.
+    interval: Duration,
+    dir: PathBuf,
+    /// Size in bytes after which a new file is started.
+    max_file_size: u64,
+    /// Files kept, the oldest are deleted.
+    max_files: usize,
+    /// Stop after this many rounds, run forever if unset.
+    rounds: Option<u64>,
+    /// Address to serve Prometheus metrics on, disabled if unset.
+    metrics: Option<SocketAddr>,
+}
+
+impl MonitorConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            interval: Duration::from_secs(env_or("MONITOR_INTERVAL_S", 300)?),
+            dir: env_or("MONITOR_DIR", PathBuf::from("./monitor"))?,
+            max_file_size: env_or::<u64>("MONITOR_FILE_MB", 16)? * 1024 * 1024,
+            max_files: env_or("MONITOR_MAX_FILES", 48)?,
+            rounds: env_opt("MONITOR_ROUNDS")?,
+            metrics: env_opt("METRICS_ADDR")?,
+        };
+        anyhow::ensure!(
+            !config.interval.is_zero(),
+            "MONITOR_INTERVAL_S must be above 0"
+        );
+        Ok(config)
+    }
+}
+
+/// Appends samples as JSON lines to `samples-<unix ms>.jsonl` files, starting a new file
+/// once the current one is full and keeping only the newest `max_files`.
+struct TimeSeries {
+    config: MonitorConfig,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl TimeSeries {
+    fn open(config: MonitorConfig) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let file = Self::create(&config)?;
+        Ok(Self {
+            config,
+            file,
+            written: 0,
+        })
+    }
+
+    fn create(config: &MonitorConfig) -> anyhow::Result<BufWriter<File>> {
+        let mut millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let mut path = config.dir.join(format!("samples-{millis:013}.jsonl"));
+        // rotating twice within a millisecond must not truncate the previous file
+        while path.exists() {
+            millis += 1;
+            path = config.dir.join(format!("samples-{millis:013}.jsonl"));
+        }
+        println!("writing samples to {}", path.display());
+        Ok(BufWriter::new(File::create(path)?))
+    }
+
+    fn append(&mut self, sample: &Sample) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(sample)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        if self.written >= self.config.max_file_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        self.file = Self::create(&self.config)?;
+        self.written = 0;
+
+        // zero padded timestamps sort by name
+        let mut files = fs::read_dir(&self.config.dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("samples-") && n.ends_with(".jsonl"))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        let excess = files.len().saturating_sub(self.config.max_files.max(1));
+        for path in &files[..excess] {
+            println!("removing {}", path.display());
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.file.flush()?)
+    }
+}
+
+/// Runs `f` on the time series on a blocking thread, appending may rotate and delete files.
+async fn with_series<T: Send + 'static>(
+    series: &Arc<Mutex<TimeSeries>>,
+    f: impl FnOnce(&mut TimeSeries) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let series = series.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut series = series
+            .lock()
+            .map_err(|_| anyhow::anyhow!("samples were left inconsistent by a failed write"))?;
+        f(&mut series)
+    })
+    .await?
+}
+
+/// The sample of a finished probe task, `None` if the task itself failed.
+pub fn sample(
+    timestamp: u64,
+    country: String,
+    result: Result<Result<RecordWithTime, RecordWithFailure>, JoinError>,
+) -> Option<Sample> {
+    match result {
+        Ok(Ok(r)) => Some(Sample {
+            timestamp,
+            ip: r.ip,
+            location: r.location,
+            country,
+            method: r.method,
+            stats: r.stats,
+            failure: None,
+            origin: r.origin,
+        }),
+        Ok(Err(r)) => Some(Sample {
+            timestamp,
+            ip: r.ip,
+            location: r.location,
+            country,
+            method: r.method,
+            stats: None,
+            failure: Some(r.reason),
+            origin: None,
+        }),
+        Err(e) => {
+            println!("Err: probe task {}", e);
+            None
+        }
+    }
+}
+
+/// Probes all targets in one round and appends a sample per target as soon as it is done.
+async fn round(
+    pinger: &Pinger,
+    records: &[RecordWithGeolocation],
+    series: &Arc<Mutex<TimeSeries>>,
+    metrics: Option<&Metrics>,
+    mut alerts: Option<&mut Alerts>,
+) -> anyhow::Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let tasks = records.iter().filter_map(|r| {
+        let country = r.country.clone();
+        let task = pinger.spawn(r.clone())?;
+        Some(async move { (country, task.await) })
+    });
+
+    let mut results = stream::iter(tasks).buffer_unordered(pinger.max_in_flight());
+    let (mut answered, mut failed) = (0, 0);
+    while let Some((country, result)) = results.next().await {
+        let Some(sample) = sample(timestamp, country, result) else {
+            continue;
+        };
+        match sample.failure {
+            Some(_) => failed += 1,
+            None => answered += 1,
+        }
+        if let Some(metrics) = metrics {
+            metrics.observe(&sample, pinger.count());
+        }
+        if let Some(alerts) = alerts.as_deref_mut() {
+            alerts.observe(&sample, pinger.count());
+        }
+        with_series(series, move |s| s.append(&sample)).await?;
+    }
+    with_series(series, TimeSeries::flush).await?;
+
+    println!("round at {timestamp}: {answered} targets answered, {failed} failed");
+    if let Some(alerts) = alerts {
+        let notifications = alerts.evaluate(timestamp);
+        alerts.notify(&notifications).await;
+    }
+    Ok(())
+}
+
+pub async fn monitor() -> anyhow::Result<()> {
+    let input = File::open("./with_geolocations.json")?;
+    let records: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+
+    let config = MonitorConfig::from_env()?;
+    println!(
+        "monitoring {} targets every {} s",
+        records.len(),
+        config.interval.as_secs()
+    );
+    let pinger = Pinger::from_env()?;
+    let rounds = config.rounds;
+    let metrics = match config.metrics {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new(metrics::target_names()?));
+            metrics::serve(addr, metrics.clone())?;
+            Some(metrics)
+        }
+        None => None,
+    };
+    let mut alerts = Alerts::from_env()?;
+    let series = Arc::new(Mutex::new(TimeSeries::open(config.clone())?));
+
+    // a round that takes longer than the interval delays the next one instead of piling up
+    let mut interval = time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut done = 0;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        tokio::select! {
+            result = round(&pinger, &records, &series, metrics.as_deref(), alerts.as_mut()) => result?,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        done += 1;
+        if rounds.is_some_and(|rounds| done >= rounds) {
+            break;
+        }
+    }
+
+    with_series(&series, TimeSeries::flush).await?;
+    println!("stopped after {done} rounds");
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, HashSet};
+use std::{fs::File, io::BufReader};
+
+use crate::config::env_or;
+use crate::distances::{geodesic_km, origin, Cities};
+use crate::geolocations::{coordinates, geolocate};
+use crate::structs::RecordWithPath;
+
+#[derive(Debug, serde::Serialize)]
+struct LocatedHop {
+    ttl: u8,
+    address: String,
+    location: String,
+    country: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct PathDistance {
+    ip: String,
+    location: String,
+    /// Distance from the origin to the target in km, as the distance stage measures it.
+    great_circle: f64,
+    /// Sum of the distances between consecutive located hops, origin to target, in km.
+    path_length: f64,
+    /// `path_length / great_circle`, 1 for a straight path.
+    detour: f64,
+    hops: Vec<LocatedHop>,
+}
+
+pub async fn calculate_path_distances() -> anyhow::Result<()> {
+    let input = File::open("./paths.json")?;
+    let paths: Vec<RecordWithPath> = serde_json::from_reader(BufReader::new(input))?;
+    // the detour of a target next to the origin is meaningless and unbounded
+    let min_distance: f64 = env_or("PATH_MIN_DISTANCE_KM", 50.)?;
+    anyhow::ensure!(min_distance > 0., "PATH_MIN_DISTANCE_KM must be above 0");
+
+    let addresses = paths
+        .iter()
+        .flat_map(|p| p.hops.iter().filter_map(|h| h.address.clone()))
+        .collect::<HashSet<_>>();
+    let hop_locations = geolocate(addresses.into_iter().collect())
+        .await?
+        .into_iter()
+        .map(|g| (g.ip.clone(), g))
+        .collect::<HashMap<_, _>>();
+
+    let here = origin()?;
+    let cities = Cities::new();
+    let mut too_close = 0;
+
+    let mut results = paths
+        .iter()
+        .filter_map(|p| {
+            let target = cities.coordinates(&p.location)?;
+            let origin = p
+                .origin
+                .as_ref()
+                .map_or(here, |o| (o.latitude, o.longitude));
+            let great_circle = geodesic_km(origin, target);
+            if great_circle < min_distance {
+                too_close += 1;
+                return None;
+            }
+
+            let hops = p
+                .hops
+                .iter()
+                .filter_map(|h| {
+                    let address = h.address.as_ref()?;
+                    if *address == p.ip {
+                        return None;
+                    }
+                    // private and other bogon addresses are not located
+                    let geolocation = hop_locations.get(address)?;
+                    let (latitude, longitude) = coordinates(geolocation)?;
+                    Some(LocatedHop {
+                        ttl: h.ttl,
+                        address: address.clone(),
+                        location: geolocation.location.clone(),
+                        country: geolocation.country.clone(),
+                        latitude,
+                        longitude,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut points = vec![origin];
+            points.extend(hops.iter().map(|h| (h.latitude, h.longitude)));
+            points.push(target);
+            let path_length = points
+                .windows(2)
+                .map(|w| geodesic_km(w[0], w[1]))
+                .sum::<f64>();
+
+            Some(PathDistance {
+                ip: p.ip.clone(),
+                location: p.location.clone(),
+                great_circle,
+                path_length,
+                detour: path_length / great_circle,
+                hops,
+            })
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.detour.total_cmp(&a.detour));
+    println!("{too_close} targets within {min_distance} km of the origin skipped");
+
+    for r in results.iter().take(20) {
+        let countries = r
+            .hops
+            .iter()
+            .map(|h| h.country.as_str())
+            .collect::<Vec<_>>();
+        println!(
+            "{} ({}): {:.0} km direct, {:.0} km via {}, detour {:.1}x",
+            r.ip,
+            r.location,
+            r.great_circle,
+            r.path_length,
+            countries.join(" > "),
+            r.detour
+        );
+    }
+
+    let output = File::create("./path_distances.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
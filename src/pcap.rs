@@ -0,0 +1,698 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::config::env_list;
+use crate::distances::{times_file, vantage_point};
+use crate::imports::{input_path, known_locations};
+use crate::ping::rtt_stats;
+use crate::structs::{ProbeMethod, RecordWithTime};
+
+const ETH_P_ALL: u16 = 0x0003;
+const PACKET_OUTGOING: u8 = 4;
+const ARPHRD_LOOPBACK: u16 = 772;
+/// Packets without a link layer header, starting with the IP header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+
+/// Writes packets to a pcap file with nanosecond timestamps.
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&0xa1b23c4du32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Self { out })
+    }
+
+    pub fn write(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        let len = packet.len() as u32;
+        self.out
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.out
+            .write_all(&timestamp.subsec_nanos().to_le_bytes())?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(packet)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Protocol, source and destination of an IP packet, extension headers are not followed.
+fn ip_header(packet: &[u8]) -> Option<(u8, IpAddr, IpAddr, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header = usize::from(packet[0] & 0x0f) * 4;
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            Some((
+                *packet.get(9)?,
+                src.into(),
+                dst.into(),
+                packet.get(header..)?,
+            ))
+        }
+        6 => {
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            Some((*packet.get(6)?, src.into(), dst.into(), packet.get(40..)?))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a packet is a probe to, a reply from, or an ICMP error about a target.
+fn is_probe(packet: &[u8], targets: &HashSet<IpAddr>) -> bool {
+    let Some((protocol, src, dst, payload)) = ip_header(packet) else {
+        return false;
+    };
+    let involved = targets.contains(&src) || targets.contains(&dst);
+    match (protocol, payload.first()) {
+        // echo reply and request
+        (1, Some(0 | 8)) | (58, Some(128 | 129)) => involved,
+        // unreachable and time exceeded from routers quote the probe after 8 bytes
+        (1, Some(3 | 11)) | (58, Some(1 | 3)) => payload
+            .get(8..)
+            .and_then(ip_header)
+            .is_some_and(|(_, _, quoted, _)| targets.contains(&quoted)),
+        (6, _) => involved,
+        _ => false,
+    }
+}
+
+/// Receives a packet with the time the kernel received it, if `SO_TIMESTAMPNS` is set.
+fn recv_timestamped(
+    socket: &Socket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SockAddr, Option<Duration>)> {
+    // u64 for the alignment of cmsghdr
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: every pointer in the message outlives the call, the kernel writes at most
+    // the lengths given and the control messages are only read within msg_controllen
+    let ((size, timestamp), from) = unsafe {
+        SockAddr::try_init(|storage, len| {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = storage.cast();
+            msg.msg_namelen = *len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = std::mem::size_of_val(&control);
+            let size = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *len = msg.msg_namelen;
+
+            let mut timestamp = None;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                    && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+                {
+                    let ts: libc::timespec = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                    timestamp = Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            Ok((size as usize, timestamp))
+        })?
+    };
+    Ok((size, from, timestamp))
+}
+
+fn enable_timestamps(socket: &Socket) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    // SAFETY: the option value is a c_int that lives for the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            (&on as *const libc::c_int).cast(),
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Records every packet sent to or received from the targets on a background thread,
+/// both directions on all interfaces, timestamped by the kernel. Needs `CAP_NET_RAW`.
+pub struct Capture {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<u64>>,
+}
+
+impl Capture {
+    pub fn start(path: &str, targets: HashSet<IpAddr>) -> anyhow::Result<Self> {
+        let protocol = Protocol::from(i32::from(ETH_P_ALL.to_be()));
+        let socket = Socket::new(Domain::PACKET, Type::DGRAM, Some(protocol))?;
+        socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+        enable_timestamps(&socket)?;
+        let mut writer = PcapWriter::new(BufWriter::new(File::create(path)?))?;
+        println!("capturing probes to {path}");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0u8; SNAPLEN as usize];
+            let mut packets = 0;
+            while !stopped.load(Ordering::Relaxed) {
+                let (size, from, timestamp) = match recv_timestamped(&socket, &mut buf) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                };
+                // taking the time here would add the scheduling delay of this thread
+                let timestamp = timestamp.unwrap_or_else(|| {
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                });
+                // loopback delivers every packet a second time as incoming
+                if is_loopback_outgoing(&from) {
+                    continue;
+                }
+                let packet = &buf[..size];
+                if is_probe(packet, &targets) {
+                    writer.write(timestamp, packet)?;
+                    packets += 1;
+                }
+            }
+            writer.flush()?;
+            Ok(packets)
+        });
+
+        Ok(Self { stop, thread })
+    }
+
+    pub fn stop(self) -> anyhow::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let packets = self
+            .thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
+        println!("captured {packets} packets");
+        Ok(())
+    }
+}
+
+fn is_loopback_outgoing(from: &SockAddr) -> bool {
+    // sockaddr_ll: family, protocol, ifindex, hatype at 8, pkttype at 10
+    let addr =
+        unsafe { std::slice::from_raw_parts(from.as_ptr() as *const u8, from.len() as usize) };
+    let (Some(hatype), Some(pkttype)) = (addr.get(8..10), addr.get(10)) else {
+        return false;
+    };
+    u16::from_ne_bytes([hatype[0], hatype[1]]) == ARPHRD_LOOPBACK && *pkttype == PACKET_OUTGOING
+}
+
+/// Reads packets from a classic pcap file, in either byte order and time resolution.
+pub struct PcapReader<R: Read> {
+    input: R,
+    big_endian: bool,
+    nanos: bool,
+    /// Longest packet the file may contain.
+    snaplen: u32,
+    link_type: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut input: R) -> anyhow::Result<Self> {
+        let mut header = [0u8; 24];
+        input
+            .read_exact(&mut header)
+            .context("not a pcap file (too short)")?;
+        let (big_endian, nanos) = match u32::from_le_bytes(header[..4].try_into()?) {
+            0xa1b2c3d4 => (false, false),
+            0xa1b23c4d => (false, true),
+            0xd4c3b2a1 => (true, false),
+            0x4d3cb2a1 => (true, true),
+            0x0a0d0d0a => anyhow::bail!("pcapng is not supported, convert with editcap -F pcap"),
+            magic => anyhow::bail!("not a pcap file (magic {magic:#x})"),
+        };
+        let mut reader = Self {
+            input,
+            big_endian,
+            nanos,
+            snaplen: 0,
+            link_type: 0,
+        };
+        reader.snaplen = reader.u32(&header[16..20]);
+        reader.link_type = reader.u32(&header[20..24]) & 0x0fff_ffff;
+        Ok(reader)
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().expect("4 bytes");
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// Next packet with its timestamp since the epoch.
+    pub fn next_packet(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let seconds = self.u32(&header[..4]);
+        let fraction = self.u32(&header[4..8]);
+        let len = self.u32(&header[8..12]);
+        // a corrupt length would allocate up to 4 GiB
+        if len > self.snaplen {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("packet of {len} bytes exceeds the snaplen {}", self.snaplen),
+            ));
+        }
+        let (scale, limit) = if self.nanos {
+            (1, 1_000_000_000)
+        } else {
+            (1000, 1_000_000)
+        };
+        let nanos = u64::from(fraction)
+            .checked_mul(scale)
+            .filter(|_| fraction < limit)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("timestamp fraction {fraction} is not below {limit}"),
+                )
+            })?;
+        let mut packet = vec![0u8; len as usize];
+        self.input.read_exact(&mut packet)?;
+        Ok(Some((
+            Duration::from_secs(u64::from(seconds)) + Duration::from_nanos(nanos),
+            packet,
+        )))
+    }
+
+    /// Strips the link layer header, `None` for packets that are not IP.
+    pub fn ip_packet<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let offset = match self.link_type {
+            // BSD loopback
+            0 => 4,
+            // Ethernet, with one VLAN tag at most
+            1 => match frame.get(12..14)? {
+                [0x81, 0x00] => 18,
+                _ => 14,
+            },
+            101 | 228 | 229 => 0,
+            // Linux cooked capture v1 and v2
+            113 => 16,
+            276 => 20,
+            _ => return None,
+        };
+        let packet = frame.get(offset..)?;
+        matches!(packet.first()? >> 4, 4 | 6).then_some(packet)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ProbeKey {
+    Icmp {
+        dst: IpAddr,
+        id: u16,
+        seq: u16,
+    },
+    /// The acknowledgement number a SYN-ACK or RST to the SYN carries.
+    Tcp {
+        dst: IpAddr,
+        dst_port: u16,
+        src_port: u16,
+        ack: u32,
+    },
+}
+
+enum Packet {
+    Request(ProbeKey),
+    Reply(ProbeKey),
+}
+
+/// The probe or reply in `packet` and the address of its end on the capture host, the
+/// source of a request or the destination of a reply.
+fn classify(packet: &[u8]) -> Option<(IpAddr, Packet)> {
+    let (protocol, src, dst, payload) = ip_header(packet)?;
+    let u16_at = |i: usize| Some(u16::from_be_bytes(payload.get(i..i + 2)?.try_into().ok()?));
+    let u32_at = |i: usize| Some(u32::from_be_bytes(payload.get(i..i + 4)?.try_into().ok()?));
+    match (protocol, payload.first()?) {
+        (1, 8) | (58, 128) => Some((
+            src,
+            Packet::Request(ProbeKey::Icmp {
+                dst,
+                id: u16_at(4)?,
+                seq: u16_at(6)?,
+            }),
+        )),
+        (1, 0) | (58, 129) => Some((
+            dst,
+            Packet::Reply(ProbeKey::Icmp {
+                dst: src,
+                id: u16_at(4)?,
+                seq: u16_at(6)?,
+            }),
+        )),
+        (6, _) => {
+            let (src_port, dst_port) = (u16_at(0)?, u16_at(2)?);
+            let flags = *payload.get(13)?;
+            let (syn, rst, ack) = (flags & 0x02 != 0, flags & 0x04 != 0, flags & 0x10 != 0);
+            if syn && !ack {
+                Some((
+                    src,
+                    Packet::Request(ProbeKey::Tcp {
+                        dst,
+                        dst_port,
+                        src_port,
+                        ack: u32_at(4)?.wrapping_add(1),
+                    }),
+                ))
+            } else if ack && (syn || rst) {
+                // a refused connection answers as fast as an open one
+                Some((
+                    dst,
+                    Packet::Reply(ProbeKey::Tcp {
+                        dst: src,
+                        dst_port: src_port,
+                        src_port: dst_port,
+                        ack: u32_at(8)?,
+                    }),
+                ))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Destination {
+    icmp_sent: usize,
+    icmp: Vec<f64>,
+    tcp_sent: usize,
+    tcp: Vec<f64>,
+}
+
+/// The address that sent the most requests in each family. Without it configured that
+/// is taken to be the capture host, unless the host was probed more than it probed.
+fn capture_host(packets: &[(Duration, IpAddr, Packet)]) -> HashSet<IpAddr> {
+    let mut requests: HashMap<IpAddr, usize> = HashMap::new();
+    for (_, local, packet) in packets {
+        if let Packet::Request(_) = packet {
+            *requests.entry(*local).or_default() += 1;
+        }
+    }
+    let most = |v6: bool| {
+        requests
+            .iter()
+            .filter(|(ip, _)| ip.is_ipv6() == v6)
+            .max_by_key(|(ip, n)| (**n, **ip))
+            .map(|(ip, _)| *ip)
+    };
+    most(false).into_iter().chain(most(true)).collect()
+}
+
+/// Pairs the requests `local` sent with the replies it received, returns the destinations
+/// and how many replies were skipped because their request was retransmitted.
+fn pair(
+    packets: Vec<(Duration, IpAddr, Packet)>,
+    local: &HashSet<IpAddr>,
+) -> (BTreeMap<IpAddr, Destination>, usize) {
+    // requests waiting for a reply and whether they were retransmitted
+    let mut pending: HashMap<ProbeKey, (Duration, bool)> = HashMap::new();
+    let mut destinations: BTreeMap<IpAddr, Destination> = BTreeMap::new();
+    let mut ambiguous = 0;
+    // probes other hosts sent to the capture host or through it
+    for (timestamp, _, packet) in packets.into_iter().filter(|(_, l, _)| local.contains(l)) {
+        match packet {
+            Packet::Request(key) => {
+                let (dst, tcp) = match key {
+                    ProbeKey::Icmp { dst, .. } => (dst, false),
+                    ProbeKey::Tcp { dst, .. } => (dst, true),
+                };
+                let destination = destinations.entry(dst).or_default();
+                if tcp {
+                    destination.tcp_sent += 1;
+                } else {
+                    destination.icmp_sent += 1;
+                }
+                pending
+                    .entry(key)
+                    .and_modify(|(_, retransmitted)| *retransmitted = true)
+                    .or_insert((timestamp, false));
+            }
+            Packet::Reply(key) => {
+                let Some((sent, retransmitted)) = pending.remove(&key) else {
+                    continue;
+                };
+                // the reply may belong to any of the copies
+                if retransmitted {
+                    ambiguous += 1;
+                    continue;
+                }
+                let rtt = timestamp.saturating_sub(sent).as_secs_f64();
+                let destination = destinations
+                    .entry(match key {
+                        ProbeKey::Icmp { dst, .. } | ProbeKey::Tcp { dst, .. } => dst,
+                    })
+                    .or_default();
+                match key {
+                    ProbeKey::Icmp { .. } => destination.icmp.push(rtt),
+                    ProbeKey::Tcp { .. } => destination.tcp.push(rtt),
+                }
+            }
+        }
+    }
+    (destinations, ambiguous)
+}
+
+/// Pairs echo requests with replies and SYNs with SYN-ACKs the capture host sent and
+/// received in the capture given as argument, and writes the RTTs per destination like
+/// the ping stage does. `PCAP_LOCAL` lists the addresses of the capture host.
+pub fn import_pcap() -> anyhow::Result<()> {
+    let path = input_path("import-pcap")?;
+    let mut reader = PcapReader::new(BufReader::new(File::open(&path)?))?;
+    let mut local = env_list("PCAP_LOCAL", "")
+        .iter()
+        .map(|ip| ip.parse())
+        .collect::<Result<HashSet<IpAddr>, _>>()
+        .context("invalid address in PCAP_LOCAL")?;
+
+    let mut packets = Vec::new();
+    let mut total = 0;
+    while let Some((timestamp, frame)) = reader.next_packet()? {
+        total += 1;
+        if let Some((end, packet)) = reader.ip_packet(&frame).and_then(classify) {
+            packets.push((timestamp, end, packet));
+        }
+    }
+    if local.is_empty() {
+        local = capture_host(&packets);
+        let mut hosts = local.iter().map(IpAddr::to_string).collect::<Vec<_>>();
+        hosts.sort();
+        println!(
+            "assuming the capture host is {}, set PCAP_LOCAL otherwise",
+            hosts.join(", ")
+        );
+    }
+    let (destinations, ambiguous) = pair(packets, &local);
+
+    let locations = known_locations()?;
+    // the capture was taken elsewhere, ORIGIN_* describe where
+    let origin = vantage_point()?;
+    let results = destinations
+        .into_iter()
+        .filter_map(|(ip, d)| {
+            let (method, samples, sent) = if d.icmp.is_empty() {
+                (ProbeMethod::Tcp, d.tcp, d.tcp_sent)
+            } else {
+                (ProbeMethod::Icmp, d.icmp, d.icmp_sent)
+            };
+            let stats = rtt_stats(samples, sent)?;
+            let ip = ip.to_string();
+            Some(RecordWithTime {
+                location: locations.get(&ip).cloned().unwrap_or_default(),
+                ip,
+                time: stats.min,
+                stats: Some(stats),
+                method,
+                http: None,
+                origin: Some(origin.clone()),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "{total} packets, {} destinations answered, {ambiguous} retransmitted probes skipped",
+        results.len()
+    );
+
+    let output = File::create(times_file()?)?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    fn ipv4(protocol: u8, src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+        packet.extend(src.octets());
+        packet.extend(dst.octets());
+        packet.extend(payload);
+        packet
+    }
+
+    fn echo(kind: u8, src: Ipv4Addr, dst: Ipv4Addr, seq: u16) -> Vec<u8> {
+        let mut icmp = vec![kind, 0, 0, 0, 0x12, 0x34];
+        icmp.extend(seq.to_be_bytes());
+        ipv4(1, src, dst, &icmp)
+    }
+
+    fn capture(packets: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for (millis, packet) in packets {
+            writer
+                .write(Duration::from_millis(*millis), packet)
+                .unwrap();
+        }
+        writer.out
+    }
+
+    fn read(file: Vec<u8>) -> Vec<(Duration, IpAddr, Packet)> {
+        let mut reader = PcapReader::new(Cursor::new(file)).unwrap();
+        let mut packets = Vec::new();
+        while let Some((timestamp, frame)) = reader.next_packet().unwrap() {
+            if let Some((end, packet)) = reader.ip_packet(&frame).and_then(classify) {
+                packets.push((timestamp, end, packet));
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn pairs_only_probes_of_the_capture_host() {
+        let file = capture(&[
+            (0, echo(8, HOST, TARGET, 1)),
+            // the peer pings the capture host, with the same identifier and sequence
+            (5, echo(8, PEER, HOST, 1)),
+            (6, echo(0, HOST, PEER, 1)),
+            (10, echo(8, HOST, TARGET, 2)),
+            (20, echo(0, TARGET, HOST, 1)),
+            (40, echo(0, TARGET, HOST, 2)),
+            (50, echo(8, HOST, TARGET, 3)),
+        ]);
+        let packets = read(file);
+        let local = capture_host(&packets);
+        assert_eq!(local, HashSet::from([IpAddr::from(HOST)]));
+
+        let (destinations, ambiguous) = pair(packets, &local);
+        assert_eq!(ambiguous, 0);
+        assert_eq!(
+            destinations.keys().collect::<Vec<_>>(),
+            [&IpAddr::from(TARGET)]
+        );
+        let target = &destinations[&IpAddr::from(TARGET)];
+        assert_eq!(target.icmp_sent, 3);
+        assert_eq!(target.icmp, [0.02, 0.03]);
+    }
+
+    #[test]
+    fn router_errors_about_targets() {
+        let targets = HashSet::from([IpAddr::from(TARGET)]);
+        let router = Ipv4Addr::new(10, 0, 0, 1);
+        let time_exceeded = |probe: Vec<u8>| {
+            let mut icmp = vec![11, 0, 0, 0, 0, 0, 0, 0];
+            icmp.extend(probe);
+            ipv4(1, router, HOST, &icmp)
+        };
+        assert!(is_probe(&echo(8, HOST, TARGET, 1), &targets));
+        assert!(is_probe(&time_exceeded(echo(8, HOST, TARGET, 1)), &targets));
+        // e.g. a traceroute someone else runs on the same host
+        assert!(!is_probe(&time_exceeded(echo(8, HOST, PEER, 1)), &targets));
+        assert!(!is_probe(&echo(8, HOST, PEER, 1), &targets));
+    }
+
+    #[test]
+    fn oversized_record() {
+        let mut file = capture(&[(0, echo(8, HOST, TARGET, 1))]);
+        // incl_len of the first record
+        file[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = PcapReader::new(Cursor::new(file)).unwrap();
+        let e = reader.next_packet().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_timestamp_fraction() {
+        let file = capture(&[(0, echo(8, HOST, TARGET, 1))]);
+        let read_fraction = |magic: u32, fraction: u32| {
+            let mut file = file.clone();
+            file[..4].copy_from_slice(&magic.to_le_bytes());
+            // ts_usec (or ts_nsec) of the first record
+            file[28..32].copy_from_slice(&fraction.to_le_bytes());
+            let mut reader = PcapReader::new(Cursor::new(file)).unwrap();
+            reader.next_packet().map(|p| p.unwrap().0)
+        };
+
+        let micros = 0xa1b2c3d4;
+        assert_eq!(
+            read_fraction(micros, 999_999).unwrap(),
+            Duration::from_micros(999_999)
+        );
+        for fraction in [1_000_000, u32::MAX] {
+            let e = read_fraction(micros, fraction).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+
+        let nanos = 0xa1b23c4d;
+        assert_eq!(
+            read_fraction(nanos, 999_999_999).unwrap(),
+            Duration::from_nanos(999_999_999)
+        );
+        let e = read_fraction(nanos, 1_000_000_000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
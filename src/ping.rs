@@ -1,76 +1,428 @@
 use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fs::File, io::BufReader};
 
-use futures::future::join_all;
+use futures::{stream, StreamExt};
 use rand::random;
-use surge_ping::{Client, Config, IcmpPacket, PingIdentifier, PingSequence, ICMP};
-use tokio::time;
+use socket2::Type;
+use surge_ping::{Client, IcmpPacket, PingIdentifier, PingSequence, SurgeError};
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
 
-use crate::structs::{RecordWithGeolocation, RecordWithTime};
+use crate::config::{env_list, env_opt, env_or};
+use crate::distances::vantage_point;
+use crate::failures::{classify_icmp, dominant, ErrorListener};
+use crate::pcap::Capture;
+use crate::probers::{icmp_clients, IcmpClients, SocketOptions};
+use crate::scheduler::{Scheduler, SchedulerConfig};
+use crate::structs::{
+    FailureReason, ProbeMethod, RecordWithFailure, RecordWithGeolocation, RecordWithTime, RttStats,
+};
+use crate::tcp;
+
+/// Which probers `ping_ips` uses, selected with `PING_METHOD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodSelection {
+    Icmp,
+    Tcp,
+    /// ICMP first, TCP for targets that did not answer.
+    Auto,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub method: MethodSelection,
+    /// Number of probes sent to every target.
+    pub count: u16,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// ICMP payload in bytes.
+    pub payload_size: usize,
+    /// Ports tried in order by the TCP prober.
+    pub tcp_ports: Vec<u16>,
+    pub socket: SocketOptions,
+}
+
+impl ProbeConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let method = match env_or("PING_METHOD", "icmp".to_string())?.as_str() {
+            "icmp" => MethodSelection::Icmp,
+            "tcp" => MethodSelection::Tcp,
+            "auto" => MethodSelection::Auto,
+            other => anyhow::bail!("unknown PING_METHOD: {other}"),
+        };
+        let tcp_ports = env_list("TCP_PORTS", "443,80")
+            .iter()
+            .map(|p| p.parse())
+            .collect::<Result<Vec<u16>, _>>()?;
+
+        let config = Self {
+            method,
+            count: env_or("PING_COUNT", 5)?,
+            interval: Duration::from_millis(env_or("PING_INTERVAL_MS", 200)?),
+            timeout: Duration::from_millis(env_or("PING_TIMEOUT_MS", 5000)?),
+            payload_size: env_or("PING_PAYLOAD_SIZE", 56)?,
+            tcp_ports,
+            socket: SocketOptions::from_env()?,
+        };
+        // no probes would report every target as lost
+        anyhow::ensure!(config.count >= 1, "PING_COUNT must be at least 1");
+        anyhow::ensure!(
+            !config.interval.is_zero(),
+            "PING_INTERVAL_MS must be above 0"
+        );
+        Ok(config)
+    }
+}
+
+/// Replies and failures of all probes sent to one target.
+#[derive(Debug, Default)]
+pub struct ProbeResults {
+    /// Round trip times in seconds.
+    pub samples: Vec<f64>,
+    pub failures: Vec<FailureReason>,
+}
+
+/// Probers and pacing shared by every target of a run.
+pub struct Pinger {
+    config: Arc<ProbeConfig>,
+    scheduler: Arc<Scheduler>,
+    clients: IcmpClients,
+    listener: Option<ErrorListener>,
+}
+
+impl Pinger {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::new(ProbeConfig::from_env()?, SchedulerConfig::from_env()?)
+    }
+
+    pub fn new(config: ProbeConfig, scheduler: SchedulerConfig) -> anyhow::Result<Self> {
+        let scheduler = Scheduler::new(scheduler);
+        let clients = match config.method {
+            MethodSelection::Tcp => IcmpClients { v4: None, v6: None },
+            MethodSelection::Icmp => icmp_clients(true, &config.socket)?,
+            MethodSelection::Auto => icmp_clients(false, &config.socket)?,
+        };
+        let listener = ErrorListener::spawn(config.timeout);
+        if listener.is_none() && config.method != MethodSelection::Tcp {
+            println!("warning: no raw socket, ICMP errors from routers will show up as timeouts");
+        }
+        Ok(Self {
+            config: Arc::new(config),
+            scheduler: Arc::new(scheduler),
+            clients,
+            listener,
+        })
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.scheduler.max_in_flight()
+    }
+
+    /// Probes sent to each target.
+    pub fn count(&self) -> u16 {
+        self.config.count
+    }
+
+    /// Probes `record` on its own task, `None` if its address does not parse.
+    pub fn spawn(
+        &self,
+        record: RecordWithGeolocation,
+    ) -> Option<JoinHandle<Result<RecordWithTime, RecordWithFailure>>> {
+        let addr: IpAddr = match record.ip.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                println!("{} parse to ipaddr error: {}", record.ip, e);
+                return None;
+            }
+        };
+        let client = match addr {
+            IpAddr::V4(_) => self.clients.v4.clone(),
+            IpAddr::V6(_) => self.clients.v6.clone(),
+        };
+        Some(tokio::spawn(ping(
+            client,
+            addr,
+            record,
+            self.config.clone(),
+            self.scheduler.clone(),
+            self.listener.clone(),
+        )))
+    }
+}
 
 pub async fn ping_ips() -> anyhow::Result<()> {
     let input = File::open("./with_geolocations.json")?;
     let records: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
 
-    let mut tasks = Vec::new();
-    let client_v4 = Client::new(&Config::default())?;
-    let client_v6 = Client::new(&Config::builder().kind(ICMP::V6).build())?;
-
-    for r in records {
-        //NOTE: the throttle here is arbitrary, higher values might produce more accurate results
-        time::sleep(Duration::from_millis(50)).await;
-        match r.ip.parse() {
-            Ok(IpAddr::V4(addr)) => {
-                tasks.push(tokio::spawn(ping(client_v4.clone(), IpAddr::V4(addr), r)))
-            }
-            Ok(IpAddr::V6(addr)) => {
-                tasks.push(tokio::spawn(ping(client_v6.clone(), IpAddr::V6(addr), r)))
-            }
-            Err(e) => println!("{} parse to ipaddr error: {}", r.ip, e),
+    let capture = match env_opt::<String>("PCAP_FILE")? {
+        Some(path) => {
+            let targets = records.iter().filter_map(|r| r.ip.parse().ok()).collect();
+            Some(Capture::start(&path, targets)?)
         }
-    }
+        None => None,
+    };
+
+    let pinger = Pinger::from_env()?;
+    let tasks = records.into_iter().filter_map(|r| pinger.spawn(r));
 
-    let results = join_all(tasks).await;
+    // each target has at most one probe in flight, so this bounds the probes in flight
+    let results = stream::iter(tasks)
+        .buffer_unordered(pinger.max_in_flight())
+        .collect::<Vec<_>>()
+        .await;
+    let mut crashed = 0;
+    let results = results
+        .into_iter()
+        .filter_map(|r| {
+            r.map_err(|e| {
+                println!("Err: probe task {}", e);
+                crashed += 1;
+            })
+            .ok()
+        })
+        .collect::<Vec<_>>();
+    let (results, failures): (Vec<_>, Vec<_>) = results.into_iter().partition(|r| r.is_ok());
+    let origin = vantage_point()?;
     let results = results
         .into_iter()
-        .filter_map(|r| r.ok())
         .flatten()
+        .map(|r| RecordWithTime {
+            origin: Some(origin.clone()),
+            ..r
+        })
+        .collect::<Vec<_>>();
+    let failures = failures
+        .into_iter()
+        .filter_map(|r| r.err())
         .collect::<Vec<_>>();
+    println!(
+        "{} targets answered, {} failed, {} probe tasks crashed",
+        results.len(),
+        failures.len(),
+        crashed
+    );
+    if let Some(capture) = capture {
+        capture.stop()?;
+    }
 
     let output = File::create("./with_times.json")?;
     serde_json::to_writer_pretty(output, &results)?;
 
+    let output = File::create("./failures.json")?;
+    serde_json::to_writer_pretty(output, &failures)?;
+
     Ok(())
 }
 
 async fn ping(
-    client: Client,
+    client: Option<Client>,
     addr: IpAddr,
     record: RecordWithGeolocation,
-) -> Option<RecordWithTime> {
-    let payload = [0; 56];
-    let mut pinger = client.pinger(addr, PingIdentifier(random())).await;
-    pinger.timeout(Duration::from_secs(5));
-
-    let res = match pinger.ping(PingSequence(0), &payload).await {
-        Ok((IcmpPacket::V4(_), dur)) => Some(RecordWithTime {
-            ip: record.ip.clone(),
-            location: record.location.clone(),
-            time: dur.as_secs_f64(),
+    config: Arc<ProbeConfig>,
+    scheduler: Arc<Scheduler>,
+    listener: Option<ErrorListener>,
+) -> Result<RecordWithTime, RecordWithFailure> {
+    let mut method = ProbeMethod::Icmp;
+    let mut results = match client {
+        Some(client) => {
+            let payload_size = config.payload_size;
+            ping_icmp(
+                client,
+                addr,
+                payload_size,
+                &config,
+                &scheduler,
+                listener.as_ref(),
+            )
+            .await
+        }
+        // without a fallback a missing socket is a local problem, not a lost target
+        None if config.method == MethodSelection::Icmp => ProbeResults {
+            samples: Vec::new(),
+            failures: vec![FailureReason::SocketError {
+                message: format!(
+                    "no ICMP socket for IPv{}",
+                    if addr.is_ipv4() { 4 } else { 6 }
+                ),
+            }],
+        },
+        None => ProbeResults::default(),
+    };
+    if results.samples.is_empty() && config.method != MethodSelection::Icmp {
+        method = ProbeMethod::Tcp;
+        let icmp_failures = results.failures;
+        results = tcp::probe(addr, &config, &scheduler).await;
+        // an ICMP error explains a failed handshake better than its timeout
+        results.failures.extend(icmp_failures);
+    }
+
+    println!("[+] {} done.", addr);
+
+    match rtt_stats(results.samples, config.count as usize) {
+        Some(stats) => Ok(RecordWithTime {
+            ip: record.ip,
+            location: record.location,
+            time: stats.min,
+            stats: Some(stats),
+            method,
+            http: None,
+            origin: None,
         }),
-        Ok((IcmpPacket::V6(_), dur)) => Some(RecordWithTime {
-            ip: record.ip.clone(),
-            location: record.location.clone(),
-            time: dur.as_secs_f64(),
+        None => Err(RecordWithFailure {
+            ip: record.ip,
+            location: record.location,
+            country: record.country,
+            method,
+            reason: dominant(results.failures),
         }),
-        Err(e) => {
-            println!("Err: {} ping {}", pinger.host, e);
-            None
+    }
+}
+
+/// Sends `config.count` echo requests with `payload_size` bytes of payload. Errors from
+/// routers are only told apart from timeouts if a `listener` is given.
+pub async fn ping_icmp(
+    client: Client,
+    addr: IpAddr,
+    payload_size: usize,
+    config: &ProbeConfig,
+    scheduler: &Scheduler,
+    listener: Option<&ErrorListener>,
+) -> ProbeResults {
+    let payload = vec![0; payload_size];
+    let identifier = random();
+    // datagram sockets replace the identifier with their port on the way out
+    let sent_identifier = (client.get_socket().get_type() != Type::DGRAM).then_some(identifier);
+    let mut pinger = client.pinger(addr, PingIdentifier(identifier)).await;
+    pinger.timeout(config.timeout);
+
+    let mut interval = time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut results = ProbeResults::default();
+    for seq in 0..config.count {
+        interval.tick().await;
+        scheduler.wait(addr).await;
+        match pinger.ping(PingSequence(seq), &payload).await {
+            // the target itself can answer with an error instead of an echo reply
+            Ok((IcmpPacket::V4(p), dur)) if p.get_icmp_type().0 == 0 => {
+                results.samples.push(dur.as_secs_f64())
+            }
+            Ok((IcmpPacket::V6(p), dur)) if p.get_icmpv6_type().0 == 129 => {
+                results.samples.push(dur.as_secs_f64())
+            }
+            Ok((IcmpPacket::V4(p), _)) => results.failures.push(classify_icmp(
+                false,
+                p.get_icmp_type().0,
+                p.get_icmp_code().0,
+            )),
+            Ok((IcmpPacket::V6(p), _)) => results.failures.push(classify_icmp(
+                true,
+                p.get_icmpv6_type().0,
+                p.get_icmpv6_code().0,
+            )),
+            Err(SurgeError::Timeout { .. }) => results.failures.push(
+                listener
+                    .and_then(|l| l.take(addr, sent_identifier, seq))
+                    .unwrap_or(FailureReason::Timeout),
+            ),
+            Err(e) => results.failures.push(FailureReason::SocketError {
+                message: e.to_string(),
+            }),
         }
+    }
+
+    results
+}
+
+/// Summarises the replies to `sent` probes, `None` if none came back. Duplicated replies
+/// can outnumber the probes, loss is zero then.
+pub fn rtt_stats(samples: Vec<f64>, sent: usize) -> Option<RttStats> {
+    if samples.is_empty() {
+        return None;
+    }
+    let sent = sent.max(samples.len());
+
+    let n = samples.len() as f64;
+    let avg = samples.iter().sum::<f64>() / n;
+    let variance = samples.iter().map(|s| (s - avg).powi(2)).sum::<f64>() / n;
+    let jitter = if samples.len() > 1 {
+        samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (n - 1f64)
+    } else {
+        0f64
     };
 
-    println!("[+] {} done.", pinger.host);
+    let mut sorted = samples.clone();
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    let median = if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2f64
+    } else {
+        sorted[mid]
+    };
+
+    Some(RttStats {
+        min: sorted[0],
+        avg,
+        median: Some(median),
+        max: sorted[sorted.len() - 1],
+        stddev: variance.sqrt(),
+        jitter: Some(jitter),
+        loss: sent.saturating_sub(samples.len()) as f64 / sent as f64 * 100f64,
+        sent,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
 
-    res
-}
\ No newline at end of file
+    use super::*;
+
+    #[tokio::test]
+    async fn missing_socket_is_not_a_timeout() {
+        let config = ProbeConfig {
+            method: MethodSelection::Icmp,
+            count: 3,
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(300),
+            payload_size: 56,
+            tcp_ports: Vec::new(),
+            socket: SocketOptions::default(),
+        };
+        let scheduler = Scheduler::new(SchedulerConfig {
+            rate: 1000.,
+            burst: 100.,
+            max_in_flight: 1,
+            prefix_spacing: Duration::ZERO,
+        });
+        let addr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let record = RecordWithGeolocation {
+            ip: addr.to_string(),
+            location: "Cologne".to_string(),
+            country: "DE".to_string(),
+            latitude: None,
+            longitude: None,
+            spread: 0.,
+            providers: Vec::new(),
+        };
+        let failure = ping(
+            None,
+            addr,
+            record,
+            Arc::new(config),
+            Arc::new(scheduler),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(failure.method, ProbeMethod::Icmp);
+        assert_eq!(
+            failure.reason,
+            FailureReason::SocketError {
+                message: "no ICMP socket for IPv6".to_string()
+            }
+        );
+    }
+}
@@ -1,17 +1,46 @@
-use std::{fs::File, io::BufReader};
+use std::collections::BTreeMap;
+use std::{env, fs::File, io::BufReader};
 
 use plotpy::{Curve, Plot};
 
+use crate::config::env_or;
+use crate::distances::distances_file;
+use crate::feasibility::{exclude_anycast, exclude_infeasible, is_feasible};
 use crate::structs::RecordWithDistance;
 
-pub fn plot_data() -> anyhow::Result<()> {
-    let input = File::open("./with_distances.json")?;
-    let records: Vec<RecordWithDistance> = serde_json::from_reader(BufReader::new(input))?;
+const COLORS: [&str; 10] = [
+    "tab:blue",
+    "tab:orange",
+    "tab:green",
+    "tab:red",
+    "tab:purple",
+    "tab:brown",
+    "tab:pink",
+    "tab:gray",
+    "tab:olive",
+    "tab:cyan",
+];
+
+fn load_records(path: &str) -> anyhow::Result<Vec<RecordWithDistance>> {
+    let input = File::open(path)?;
+    let mut records: Vec<RecordWithDistance> = serde_json::from_reader(BufReader::new(input))?;
+    if exclude_infeasible()? {
+        records.retain(is_feasible);
+    }
+    if exclude_anycast()? {
+        records.retain(|r| !r.anycast);
+    }
+    Ok(records)
+}
 
+fn curve(records: &[RecordWithDistance], color: &str, label: &str) -> Curve {
     let mut curve = Curve::new();
     curve.set_line_style("None");
     curve.set_marker_style("o");
     curve.set_marker_size(1.5);
+    curve.set_marker_color(color);
+    curve.set_marker_line_color(color);
+    curve.set_label(label);
 
     curve.points_begin();
     for r in records {
@@ -19,8 +48,70 @@ pub fn plot_data() -> anyhow::Result<()> {
     }
     curve.points_end();
 
+    curve
+}
+
+/// Records grouped by the id of the origin they were measured from.
+fn by_origin(records: Vec<RecordWithDistance>) -> BTreeMap<String, Vec<RecordWithDistance>> {
+    let mut origins: BTreeMap<String, Vec<RecordWithDistance>> = BTreeMap::new();
+    for r in records {
+        let id = r.origin.as_ref().map_or("unknown", |o| o.id.as_str());
+        origins.entry(id.to_string()).or_default().push(r);
+    }
+    origins
+}
+
+/// One subplot per origin in `plot_origins.svg`.
+fn plot_facets(origins: &BTreeMap<String, Vec<RecordWithDistance>>) -> anyhow::Result<()> {
+    anyhow::ensure!(!origins.is_empty(), "no records to plot");
+    let columns = (origins.len() as f64).sqrt().ceil() as usize;
+    let rows = origins.len().div_ceil(columns);
+
     let mut plot = Plot::new();
-    plot.add(&curve)
+    for (i, (id, records)) in origins.iter().enumerate() {
+        plot.set_subplot(rows, columns, i + 1)
+            .add(&curve(records, COLORS[0], id))
+            .grid_and_labels("Time (ms)", "Distance (km)")
+            .set_title(id);
+    }
+    plot.set_figure_size_points(500. * columns as f64, 300. * rows as f64);
+    plot.save("plot_origins.svg").unwrap();
+
+    Ok(())
+}
+
+pub fn plot_data() -> anyhow::Result<()> {
+    let path = distances_file()?;
+    let (anycast, records): (Vec<_>, Vec<_>) =
+        load_records(&path)?.into_iter().partition(|r| r.anycast);
+    // "color" gives every origin its own colour, "facet" also plots each on its own
+    let mut curves = match env_or("PLOT_BY_ORIGIN", String::new())?.as_str() {
+        "" => vec![curve(&records, COLORS[0], &path)],
+        mode @ ("color" | "facet") => {
+            let origins = by_origin(records);
+            if mode == "facet" {
+                plot_facets(&origins)?;
+            }
+            origins
+                .iter()
+                .enumerate()
+                .map(|(i, (id, records))| curve(records, COLORS[i % COLORS.len()], id))
+                .collect()
+        }
+        other => anyhow::bail!("unknown PLOT_BY_ORIGIN: {other}"),
+    };
+    // close from everywhere, they would pull the fit towards zero distance
+    if !anycast.is_empty() {
+        curves.push(curve(&anycast, "black", "anycast"));
+    }
+    // e.g. http results next to the ping results
+    if let Ok(compare) = env::var("PLOT_COMPARE") {
+        let color = COLORS[curves.len() % COLORS.len()];
+        curves.push(curve(&load_records(&compare)?, color, &compare));
+    }
+
+    let mut plot = Plot::new();
+    add_curves(&mut plot, &curves)
         .grid_and_labels("Time (ms)", "Distance (km)")
         .set_title("Ping time vs. distance")
         .set_figure_size_points(1000., 600.)
@@ -28,7 +119,7 @@ pub fn plot_data() -> anyhow::Result<()> {
     plot.save("plot.svg").unwrap();
 
     let mut plot = Plot::new();
-    plot.add(&curve)
+    add_curves(&mut plot, &curves)
         .grid_and_labels("Time (ms)", "Distance (km)")
         .set_title("Ping time vs. distance (log-log)")
         .set_figure_size_points(1000., 600.)
@@ -38,7 +129,7 @@ pub fn plot_data() -> anyhow::Result<()> {
     plot.save("plot_log.svg").unwrap();
 
     let mut plot = Plot::new();
-    plot.add(&curve)
+    add_curves(&mut plot, &curves)
         .grid_and_labels("Time (ms)", "Distance (km)")
         .set_title("Ping time vs. distance (log-log)")
         .set_range(0., 100., 0., 2500.)
@@ -46,4 +137,14 @@ pub fn plot_data() -> anyhow::Result<()> {
     plot.save("plot_crop.svg").unwrap();
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn add_curves<'a>(plot: &'a mut Plot, curves: &[Curve]) -> &'a mut Plot {
+    for curve in curves {
+        plot.add(curve);
+    }
+    if curves.len() > 1 {
+        plot.legend();
+    }
+    plot
+}
@@ -0,0 +1,231 @@
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::BorrowedFd;
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use surge_ping::{Client, Config, ICMP};
+use tokio::io::unix::AsyncFd;
+
+use crate::config::{env_opt, env_or};
+
+/// Socket level settings shared by the ICMP and TCP probers.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    pub ttl: Option<u32>,
+    /// Differentiated services code point (0-63), written to the TOS/traffic class.
+    pub dscp: Option<u8>,
+    /// Source address, only applied to sockets of the same family.
+    pub source: Option<IpAddr>,
+    pub interface: Option<String>,
+}
+
+impl SocketOptions {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let options = Self {
+            ttl: env_opt("PING_TTL")?,
+            dscp: env_opt("PING_DSCP")?,
+            source: env_opt("PING_SOURCE")?,
+            interface: env_opt("PING_INTERFACE")?,
+        };
+        if options.dscp.is_some_and(|d| d > 63) {
+            anyhow::bail!("PING_DSCP must be between 0 and 63");
+        }
+        Ok(options)
+    }
+
+    /// Applies TTL and DSCP to a socket of the given family.
+    pub fn apply(&self, socket: SockRef, v6: bool) -> std::io::Result<()> {
+        if let Some(ttl) = self.ttl {
+            if v6 {
+                socket.set_unicast_hops_v6(ttl)?;
+            } else {
+                socket.set_ttl(ttl)?;
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            let tos = u32::from(dscp) << 2;
+            if v6 {
+                socket.set_tclass_v6(tos)?;
+            } else {
+                socket.set_tos(tos)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Local address to bind to for `ip`'s family, if a source of that family is set.
+    pub fn source_for(&self, ip: IpAddr) -> Option<IpAddr> {
+        self.source.filter(|s| s.is_ipv4() == ip.is_ipv4())
+    }
+
+    fn icmp_client(&self, kind: ICMP, socket_type: Type) -> anyhow::Result<Client> {
+        let mut config = Config::builder().kind(kind).sock_type_hint(socket_type);
+        if let Some(interface) = &self.interface {
+            config = config.interface(interface);
+        }
+        let unspecified = match kind {
+            ICMP::V4 => IpAddr::from([0u8; 4]),
+            ICMP::V6 => IpAddr::from([0u16; 8]),
+        };
+        if let Some(source) = self.source_for(unspecified) {
+            config = config.bind(SocketAddr::new(source, 0));
+        }
+        let client = Client::new(&config.build())?;
+
+        // surge-ping has no DSCP setting and sets the TTL with the IPv4 option only
+        let fd = client.get_socket().get_native_sock();
+        // SAFETY: the client owns the descriptor and keeps it open for this call
+        let socket = unsafe { BorrowedFd::borrow_raw(fd) };
+        self.apply(SockRef::from(&socket), matches!(kind, ICMP::V6))?;
+
+        Ok(client)
+    }
+}
+
+/// ICMP clients for both address families, `None` where no socket could be opened.
+pub struct IcmpClients {
+    pub v4: Option<Client>,
+    pub v6: Option<Client>,
+}
+
+/// Finds the socket type to use for ICMP, preferring the unprivileged datagram socket
+/// (`net.ipv4.ping_group_range`) over a raw socket (root or CAP_NET_RAW).
+fn icmp_socket_type(domain: Domain, protocol: Protocol, hint: &str) -> Option<Type> {
+    let candidates: &[Type] = match hint {
+        "dgram" => &[Type::DGRAM],
+        "raw" => &[Type::RAW],
+        _ => &[Type::DGRAM, Type::RAW],
+    };
+    candidates
+        .iter()
+        .copied()
+        .find(|t| Socket::new(domain, *t, Some(protocol)).is_ok())
+}
+
+fn describe(socket: Option<Type>) -> &'static str {
+    match socket {
+        Some(Type::DGRAM) => "unprivileged datagram socket",
+        Some(Type::RAW) => "raw socket",
+        _ => "unavailable",
+    }
+}
+
+fn icmp_socket_hint() -> anyhow::Result<String> {
+    let hint: String = env_or("ICMP_SOCKET", "auto".to_string())?;
+    if !["auto", "dgram", "raw"].contains(&hint.as_str()) {
+        anyhow::bail!("unknown ICMP_SOCKET: {hint}, expected auto, dgram or raw");
+    }
+    Ok(hint)
+}
+
+fn unavailable_error() -> anyhow::Error {
+    let range = fs::read_to_string("/proc/sys/net/ipv4/ping_group_range")
+        .map(|r| r.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_else(|_| "unknown".to_string());
+    anyhow::anyhow!(
+        "no ICMP socket available. Either allow unprivileged ICMP for your group \
+         (net.ipv4.ping_group_range is \"{range}\", e.g. `sudo sysctl -w net.ipv4.ping_group_range=\"0 2147483647\"`), \
+         grant raw sockets (`sudo setcap cap_net_raw+ep <pinger binary>` or run as root), \
+         or use PING_METHOD=tcp which needs no privileges"
+    )
+}
+
+/// Opens ICMP clients with the best available socket type. Fails with an explanation if
+/// ICMP is `required` but neither socket type can be opened.
+pub fn icmp_clients(required: bool, options: &SocketOptions) -> anyhow::Result<IcmpClients> {
+    let hint = icmp_socket_hint()?;
+
+    let v4 = icmp_socket_type(Domain::IPV4, Protocol::ICMPV4, &hint);
+    let v6 = icmp_socket_type(Domain::IPV6, Protocol::ICMPV6, &hint);
+    println!("icmp v4: {}, icmp v6: {}", describe(v4), describe(v6));
+
+    if v4.is_none() && v6.is_none() {
+        if required {
+            return Err(unavailable_error());
+        }
+        println!("warning: {}, falling back to tcp", unavailable_error());
+    }
+
+    Ok(IcmpClients {
+        v4: v4.map(|t| options.icmp_client(ICMP::V4, t)).transpose()?,
+        v6: v6.map(|t| options.icmp_client(ICMP::V6, t)).transpose()?,
+    })
+}
+
+/// Prints which probers can be used on this machine with the current privileges.
+pub fn report_probers() -> anyhow::Result<()> {
+    let hint = icmp_socket_hint()?;
+    let v4 = icmp_socket_type(Domain::IPV4, Protocol::ICMPV4, &hint);
+    let v6 = icmp_socket_type(Domain::IPV6, Protocol::ICMPV6, &hint);
+
+    println!("icmp v4: {}", describe(v4));
+    println!("icmp v6: {}", describe(v6));
+    println!("tcp: available");
+    if v4.is_none() && v6.is_none() {
+        println!("{}", unavailable_error());
+    }
+
+    Ok(())
+}
+
+/// Opens a raw ICMP socket for `ip`'s family. Traceroute needs one because datagram ICMP
+/// sockets only report time exceeded messages through the socket error queue.
+pub fn raw_icmp_socket(ip: IpAddr) -> anyhow::Result<Socket> {
+    let (domain, protocol) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    Socket::new(domain, Type::RAW, Some(protocol)).map_err(|e| {
+        anyhow::anyhow!(
+            "traceroute needs a raw ICMP socket ({e}), \
+             run as root or `sudo setcap cap_net_raw+ep <pinger binary>`"
+        )
+    })
+}
+
+/// A non-blocking raw ICMP socket driven by the tokio reactor.
+pub struct RawIcmpSocket {
+    inner: AsyncFd<Socket>,
+}
+
+impl RawIcmpSocket {
+    pub fn open(ip: IpAddr) -> anyhow::Result<Self> {
+        let socket = raw_icmp_socket(ip)?;
+        socket.set_nonblocking(true)?;
+        // SAFETY: the socket is owned by the AsyncFd and only closed when it is dropped
+        let inner = unsafe { AsyncFd::register(socket)? };
+        Ok(Self { inner })
+    }
+
+    pub fn socket(&self) -> &Socket {
+        self.inner.get_ref()
+    }
+
+    pub async fn send_to(&self, packet: &[u8], to: IpAddr) -> io::Result<usize> {
+        let to = SocketAddr::new(to, 0).into();
+        loop {
+            let mut guard = self.inner.writable().await?;
+            if let Ok(result) = guard.try_io(|socket| socket.get_ref().send_to(packet, &to)) {
+                return result;
+            }
+        }
+    }
+
+    /// Receives one packet, IPv4 packets start with their IP header.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, IpAddr)> {
+        // SAFETY: recv only writes initialized bytes and the buffer already is initialized
+        let uninit = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        loop {
+            let mut guard = self.inner.readable().await?;
+            if let Ok(result) = guard.try_io(|socket| socket.get_ref().recv_from(uninit)) {
+                let (size, from) = result?;
+                let from = from.as_socket().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "not an IP address")
+                })?;
+                return Ok((size, from.ip()));
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::time;
+
+use crate::config::env_or;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerConfig {
+    /// Packets per second over all targets.
+    pub rate: f64,
+    /// Packets that may be sent at once after an idle period.
+    pub burst: f64,
+    /// Targets probed at the same time, each has at most one probe in flight.
+    pub max_in_flight: usize,
+    /// Minimum gap between packets to the same /24 (/48 for IPv6).
+    pub prefix_spacing: Duration,
+}
+
+impl SchedulerConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            rate: env_or("PING_RATE", 20.)?,
+            burst: env_or("PING_BURST", 10.)?,
+            max_in_flight: env_or("PING_MAX_IN_FLIGHT", 256)?,
+            prefix_spacing: Duration::from_millis(env_or("PING_PREFIX_SPACING_MS", 100)?),
+        };
+        // no tokens or no slots would wait forever
+        anyhow::ensure!(
+            config.rate.is_finite() && config.rate > 0.,
+            "PING_RATE must be above 0"
+        );
+        anyhow::ensure!(
+            config.burst.is_finite() && config.burst >= 1.,
+            "PING_BURST must be at least 1"
+        );
+        anyhow::ensure!(
+            config.max_in_flight >= 1,
+            "PING_MAX_IN_FLIGHT must be at least 1"
+        );
+        Ok(config)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Paces outgoing probes with a token bucket and per-prefix spacing, so shared hosts do
+/// not rate limit their ICMP replies.
+pub struct Scheduler {
+    config: SchedulerConfig,
+    bucket: Mutex<Bucket>,
+    /// Earliest time the next packet to a prefix may be sent.
+    prefixes: Mutex<HashMap<u128, Instant>>,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        println!(
+            "scheduler: {} pps (burst {}), {} probes in flight, {} ms between packets to the same /24 (/48)",
+            config.rate,
+            config.burst,
+            config.max_in_flight,
+            config.prefix_spacing.as_millis()
+        );
+        Self {
+            config,
+            bucket: Mutex::new(Bucket {
+                tokens: config.burst,
+                refilled: Instant::now(),
+            }),
+            prefixes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.config.max_in_flight
+    }
+
+    fn prefix(ip: IpAddr) -> u128 {
+        match ip {
+            IpAddr::V4(ip) => u128::from(u32::from(ip) >> 8),
+            IpAddr::V6(ip) => u128::from(ip) >> 80 | 1 << 127,
+        }
+    }
+
+    /// Waits until a packet to `ip` may be sent.
+    pub async fn wait(&self, ip: IpAddr) {
+        // reserve a slot for the prefix first, then take a token once it is due
+        let slot = {
+            let mut prefixes = self.prefixes.lock().unwrap();
+            let now = Instant::now();
+            let next = prefixes.entry(Self::prefix(ip)).or_insert(now);
+            let slot = (*next).max(now);
+            *next = slot + self.config.prefix_spacing;
+            slot
+        };
+        time::sleep_until(slot.into()).await;
+
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.config.rate).min(self.config.burst);
+                bucket.refilled = now;
+                if bucket.tokens >= 1. {
+                    bucket.tokens -= 1.;
+                    return;
+                }
+                (1. - bucket.tokens) / self.config.rate
+            };
+            time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashMap, fs::File, io::BufReader};
+
+use crate::cbg::{intersect, violation, Disc, Region};
+use crate::config::env_or;
+use crate::distances::{distance_km, origin, times_file};
+use crate::feasibility::max_distance_km;
+use crate::structs::RecordWithTime;
+
+/// A target whose location is known independently of any geolocation database.
+#[derive(Debug, serde::Deserialize)]
+struct TrustedTarget {
+    ip: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SelfLocation {
+    targets: usize,
+    estimate: Region,
+    configured_latitude: f64,
+    configured_longitude: f64,
+    /// Distance between the estimate and the configured origin in km.
+    configured_error: f64,
+    /// Whether the configured origin satisfies every measured constraint.
+    configured_consistent: bool,
+}
+
+/// Multilaterates the vantage point from RTTs to targets with trusted coordinates.
+pub fn locate_self() -> anyhow::Result<()> {
+    let input = File::open(env_or(
+        "TRUSTED_TARGETS",
+        "./trusted_targets.json".to_string(),
+    )?)?;
+    let targets: Vec<TrustedTarget> = serde_json::from_reader(BufReader::new(input))?;
+    let targets = targets
+        .into_iter()
+        .map(|t| (t.ip, (t.latitude, t.longitude)))
+        .collect::<HashMap<_, _>>();
+
+    let input = File::open(times_file()?)?;
+    let records: Vec<RecordWithTime> = serde_json::from_reader(BufReader::new(input))?;
+
+    let discs = constraints(&records, &targets);
+    let configured = origin()?;
+    let Some(location) = locate(&discs, configured) else {
+        anyhow::bail!("no ping results for any trusted target");
+    };
+    let (estimate, configured_error) = (location.estimate, location.configured_error);
+
+    println!(
+        "estimated origin from {} targets: {:.3}, {:.3} ± {:.0} km",
+        discs.len(),
+        estimate.latitude,
+        estimate.longitude,
+        estimate.radius
+    );
+    if !estimate.feasible {
+        println!("warning: the constraints do not intersect, check the trusted targets");
+    }
+    if location.configured_consistent {
+        println!(
+            "configured origin {:.3}, {:.3} is consistent ({configured_error:.0} km from estimate)",
+            configured.0, configured.1
+        );
+    } else {
+        println!(
+            "warning: configured origin {:.3}, {:.3} contradicts the measurements ({configured_error:.0} km from estimate), was ORIGIN_LATITUDE/ORIGIN_LONGITUDE updated for this run?",
+            configured.0, configured.1
+        );
+    }
+
+    let output = File::create("./self_location.json")?;
+    serde_json::to_writer_pretty(output, &location)?;
+
+    Ok(())
+}
+
+/// The disc around each trusted target that the origin must lie in given its RTT.
+fn constraints(records: &[RecordWithTime], targets: &HashMap<String, (f64, f64)>) -> Vec<Disc> {
+    records
+        .iter()
+        .filter_map(|r| {
+            Some(Disc {
+                center: *targets.get(&r.ip)?,
+                radius: max_distance_km(r.time),
+            })
+        })
+        .collect()
+}
+
+/// Intersects the constraints and checks the `configured` origin against them, `None`
+/// without any constraint.
+fn locate(discs: &[Disc], configured: (f64, f64)) -> Option<SelfLocation> {
+    let estimate = intersect(discs)?;
+    Some(SelfLocation {
+        targets: discs.len(),
+        estimate,
+        configured_latitude: configured.0,
+        configured_longitude: configured.1,
+        configured_error: distance_km(configured, (estimate.latitude, estimate.longitude)),
+        configured_consistent: violation(discs, configured) <= 0.,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::ProbeMethod;
+
+    const FRANKFURT: (f64, f64) = (50.11, 8.68);
+
+    fn targets() -> HashMap<String, (f64, f64)> {
+        HashMap::from([
+            ("192.0.2.1".to_string(), (52.37, 4.90)),
+            ("192.0.2.2".to_string(), (48.14, 11.58)),
+            ("192.0.2.3".to_string(), (52.52, 13.40)),
+            ("192.0.2.4".to_string(), (48.86, 2.35)),
+        ])
+    }
+
+    /// RTTs from `origin` that allow `slack` km more than the actual distance.
+    fn records(origin: (f64, f64), slack: f64) -> Vec<RecordWithTime> {
+        let mut targets = targets().into_iter().collect::<Vec<_>>();
+        targets.sort_by(|a, b| a.0.cmp(&b.0));
+        targets
+            .into_iter()
+            .map(|(ip, center)| RecordWithTime {
+                ip,
+                location: String::new(),
+                time: (distance_km(origin, center) + slack) / max_distance_km(1.),
+                stats: None,
+                method: ProbeMethod::Icmp,
+                http: None,
+                origin: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn locates_the_origin() {
+        let discs = constraints(&records(FRANKFURT, 20.), &targets());
+        let location = locate(&discs, FRANKFURT).unwrap();
+        assert_eq!(location.targets, 4);
+        assert!(location.estimate.feasible);
+        assert!(location.configured_consistent);
+        assert!(
+            location.configured_error < 30.,
+            "{} km off",
+            location.configured_error
+        );
+
+        let madrid = (40.42, -3.70);
+        let location = locate(&discs, madrid).unwrap();
+        assert!(!location.configured_consistent);
+        assert!(location.configured_error > 1000.);
+    }
+
+    #[test]
+    fn only_trusted_targets_constrain() {
+        let mut records = records(FRANKFURT, 20.);
+        records[0].ip = "198.51.100.7".to_string();
+        let discs = constraints(&records, &targets());
+        assert_eq!(discs.len(), 3);
+        assert!(discs.iter().all(|d| d.center != (52.37, 4.90)));
+    }
+
+    #[test]
+    fn contradicting_targets() {
+        // RTTs shorter than the distances leave no point that meets all of them
+        let discs = constraints(&records(FRANKFURT, -150.), &targets());
+        let location = locate(&discs, FRANKFURT).unwrap();
+        assert!(!location.estimate.feasible);
+        assert!(location.estimate.radius > 0.);
+        assert!(!location.configured_consistent);
+
+        assert!(locate(&[], FRANKFURT).is_none());
+    }
+}
@@ -2,6 +2,10 @@
 pub struct Record {
     pub name: String,
     pub url: String,
+    /// ISO 3166 alpha-2 code from an optional `country` column, `data.csv` has none so
+    /// `collect_ips` derives it from the url and the order of the list.
+    #[serde(default)]
+    pub country: String,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
@@ -9,19 +13,160 @@ pub struct RecordWithIp {
     pub name: String,
     pub url: String,
     pub ip: String,
+    #[serde(default)]
+    pub country: String,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ProviderLocation {
+    pub provider: String,
+    pub location: String,
+    pub country: String,
+    /// `None` if the provider's coordinates did not parse, the city name may still place it.
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct RecordWithGeolocation {
     pub ip: String,
     pub location: String,
+    #[serde(default)]
+    pub country: String,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    /// Largest distance between any two providers in km.
+    #[serde(default)]
+    pub spread: f64,
+    #[serde(default)]
+    pub providers: Vec<ProviderLocation>,
+}
+
+/// The machine a measurement was taken from. Records without one were taken at the
+/// configured origin.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct VantagePoint {
+    pub id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Round trip statistics over all probes sent to a target, times in seconds. Summaries
+/// imported from other tools have no samples, median or jitter.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+pub struct RttStats {
+    pub min: f64,
+    pub avg: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub median: Option<f64>,
+    pub max: f64,
+    pub stddev: f64,
+    /// Mean absolute difference between consecutive samples.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,
+    /// Percentage of probes without a reply.
+    pub loss: f64,
+    pub sent: usize,
+    #[serde(default)]
+    pub samples: Vec<f64>,
+}
+
+impl RttStats {
+    /// Probes without a reply.
+    pub fn lost(&self) -> usize {
+        (self.loss / 100. * self.sent as f64).round() as usize
+    }
+}
+
+/// How a round trip time was measured.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeMethod {
+    /// ICMP echo request/reply.
+    #[default]
+    Icmp,
+    /// TCP SYN to SYN-ACK (or RST).
+    Tcp,
+    /// HTTP(S) request, see `HttpTiming` for the phases.
+    Http,
+}
+
+/// Duration of each phase of an HTTP(S) request in seconds.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct HttpTiming {
+    pub url: String,
+    pub dns: f64,
+    pub connect: f64,
+    /// TLS handshake, `None` for plain http.
+    pub tls: Option<f64>,
+    /// From sending the request to receiving the response head.
+    pub ttfb: f64,
+    /// From the DNS lookup to the end of the response body.
+    pub total: f64,
+    pub status: u16,
+    /// Negotiated HTTP version, e.g. `HTTP/1.1` or `HTTP/2.0`.
+    pub protocol: String,
+}
+
+/// Why a target did not answer any probe.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum FailureReason {
+    Timeout,
+    /// Destination unreachable with its ICMP code, if the error came as an ICMP message.
+    Unreachable { code: Option<u8> },
+    /// Destination unreachable because a filter rejected the probe.
+    AdminProhibited { code: u8 },
+    TtlExceeded,
+    SocketError { message: String },
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct RecordWithFailure {
+    pub ip: String,
+    pub location: String,
+    #[serde(default)]
+    pub country: String,
+    pub method: ProbeMethod,
+    #[serde(flatten)]
+    pub reason: FailureReason,
+}
+
+/// One target in one monitoring round, a line of the time series.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct Sample {
+    /// Unix time in seconds the round started at.
+    pub timestamp: u64,
+    pub ip: String,
+    pub location: String,
+    #[serde(default)]
+    pub country: String,
+    pub method: ProbeMethod,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<RttStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<FailureReason>,
+    /// Set by agents, the monitor's samples were all taken here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<VantagePoint>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct RecordWithTime {
     pub ip: String,
     pub location: String,
+    /// Minimum round trip time in seconds.
     pub time: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<RttStats>,
+    #[serde(default)]
+    pub method: ProbeMethod,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpTiming>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<VantagePoint>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
@@ -29,5 +174,37 @@ pub struct RecordWithDistance {
     pub ip: String,
     pub location: String,
     pub time: f64,
+    /// Distance from the record's origin in km.
     pub distance: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<VantagePoint>,
+    /// Flagged by the anycast stage, the distance is to no particular host.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub anycast: bool,
+}
+
+/// One TTL of a traceroute.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct Hop {
+    pub ttl: u8,
+    /// First router (or the target) that answered, `None` if every probe was lost.
+    pub address: Option<String>,
+    /// Minimum round trip time in seconds.
+    pub rtt: Option<f64>,
+    /// Percentage of probes without a reply.
+    pub loss: f64,
+    pub samples: Vec<f64>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct RecordWithPath {
+    pub ip: String,
+    pub location: String,
+    /// Whether the flow identifiers were kept constant across probes.
+    pub paris: bool,
+    /// Whether the target itself answered.
+    pub reached: bool,
+    pub hops: Vec<Hop>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<VantagePoint>,
 }
@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::{fs::File, io::BufReader};
+
+use futures::{stream, StreamExt};
+
+use crate::config::env_list;
+use crate::ping::{ping_icmp, ProbeConfig};
+use crate::probers::icmp_clients;
+use crate::scheduler::{Scheduler, SchedulerConfig};
+use crate::structs::RecordWithGeolocation;
+
+#[derive(Debug, serde::Serialize)]
+struct SizePoint {
+    /// ICMP payload in bytes.
+    size: usize,
+    /// Minimum round trip time in seconds.
+    rtt: f64,
+    samples: Vec<f64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SizeSweep {
+    ip: String,
+    location: String,
+    points: Vec<SizePoint>,
+    /// RTT extrapolated to an empty payload in seconds, i.e. propagation and processing.
+    intercept: f64,
+    /// Extra RTT per payload byte in seconds, i.e. serialization delay.
+    slope: f64,
+    /// Bottleneck bandwidth in bit/s implied by the slope (the payload crosses it twice).
+    bandwidth: Option<f64>,
+}
+
+/// Least squares fit of `y = intercept + slope * x`.
+fn fit(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+    let sxy = points
+        .iter()
+        .map(|p| (p.0 - mean_x) * (p.1 - mean_y))
+        .sum::<f64>();
+    if points.len() < 2 || sxx == 0. {
+        return None;
+    }
+    let slope = sxy / sxx;
+    Some((mean_y - slope * mean_x, slope))
+}
+
+/// Pings every target at several payload sizes and separates serialization from
+/// propagation delay by fitting RTT against size.
+pub async fn sweep_sizes() -> anyhow::Result<()> {
+    let input = File::open("./with_geolocations.json")?;
+    let records: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+
+    let sizes = env_list("PING_SIZES", "56,256,512,1024,1400")
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<usize>, _>>()?;
+    let config = Arc::new(ProbeConfig::from_env()?);
+    let scheduler = Arc::new(Scheduler::new(SchedulerConfig::from_env()?));
+    let clients = icmp_clients(true, &config.socket)?;
+    println!("payload sizes: {:?}", sizes);
+
+    let tasks = records.into_iter().filter_map(|r| {
+        let addr: IpAddr = r.ip.parse().ok()?;
+        let client = match addr {
+            IpAddr::V4(_) => clients.v4.clone(),
+            IpAddr::V6(_) => clients.v6.clone(),
+        }?;
+        let (sizes, config, scheduler) = (sizes.clone(), config.clone(), scheduler.clone());
+        Some(tokio::spawn(async move {
+            let mut points = Vec::new();
+            for size in sizes {
+                let samples = ping_icmp(client.clone(), addr, size, &config, &scheduler, None)
+                    .await
+                    .samples;
+                if let Some(rtt) = samples.iter().copied().reduce(f64::min) {
+                    points.push(SizePoint { size, rtt, samples });
+                }
+            }
+            println!("[+] {} done.", addr);
+
+            let xy = points
+                .iter()
+                .map(|p| (p.size as f64, p.rtt))
+                .collect::<Vec<_>>();
+            let (intercept, slope) = fit(&xy)?;
+            Some(SizeSweep {
+                ip: r.ip,
+                location: r.location,
+                points,
+                intercept,
+                slope,
+                bandwidth: (slope > 0.).then(|| 2. * 8. / slope),
+            })
+        }))
+    });
+
+    let results = stream::iter(tasks)
+        .buffer_unordered(scheduler.max_in_flight())
+        .filter_map(|r| async { r.ok().flatten() })
+        .collect::<Vec<_>>()
+        .await;
+
+    let output = File::create("./size_sweep.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
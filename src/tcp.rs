@@ -0,0 +1,207 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use socket2::SockRef;
+use tokio::net::TcpSocket;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::failures::classify_io;
+use crate::ping::{ProbeConfig, ProbeResults};
+use crate::probers::SocketOptions;
+use crate::scheduler::Scheduler;
+use crate::structs::FailureReason;
+
+/// Times a TCP handshake to `addr`. The connect returns once the SYN-ACK arrived, a
+/// refused connection means the host answered with a RST which is just as good a sample.
+pub async fn connect_rtt(
+    addr: SocketAddr,
+    timeout: Duration,
+    options: &SocketOptions,
+) -> Result<Duration, FailureReason> {
+    let socket = socket(addr, options).map_err(|e| FailureReason::SocketError {
+        message: e.to_string(),
+    })?;
+
+    let start = Instant::now();
+    match time::timeout(timeout, socket.connect(addr)).await {
+        Ok(Ok(_)) => Ok(start.elapsed()),
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(classify_io(&e)),
+        Err(_) => Err(FailureReason::Timeout),
+    }
+}
+
+fn socket(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    options.apply(SockRef::from(&socket), addr.is_ipv6())?;
+    if let Some(interface) = &options.interface {
+        SockRef::from(&socket).bind_device(Some(interface.as_bytes()))?;
+    }
+    if let Some(source) = options.source_for(addr.ip()) {
+        socket.bind(SocketAddr::new(source, 0))?;
+    }
+    Ok(socket)
+}
+
+/// Sends `config.count` handshakes to the first of `config.tcp_ports` that answers.
+pub async fn probe(ip: IpAddr, config: &ProbeConfig, scheduler: &Scheduler) -> ProbeResults {
+    let mut failures = Vec::new();
+    for port in &config.tcp_ports {
+        let addr = SocketAddr::new(ip, *port);
+        let mut interval = time::interval(config.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut results = ProbeResults::default();
+        for _ in 0..config.count {
+            interval.tick().await;
+            scheduler.wait(ip).await;
+            match connect_rtt(addr, config.timeout, &config.socket).await {
+                Ok(rtt) => results.samples.push(rtt.as_secs_f64()),
+                Err(reason) => {
+                    results.failures.push(reason);
+                    if results.samples.is_empty() {
+                        // nothing listening or filtered, try the next port
+                        break;
+                    }
+                }
+            }
+        }
+        if !results.samples.is_empty() {
+            return results;
+        }
+        failures.extend(results.failures);
+    }
+    ProbeResults {
+        samples: Vec::new(),
+        failures,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+    use crate::ping::{MethodSelection, Pinger};
+    use crate::scheduler::SchedulerConfig;
+    use crate::structs::{ProbeMethod, RecordWithGeolocation};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn config(tcp_ports: Vec<u16>) -> ProbeConfig {
+        ProbeConfig {
+            method: MethodSelection::Tcp,
+            count: 3,
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(300),
+            payload_size: 56,
+            tcp_ports,
+            socket: SocketOptions::default(),
+        }
+    }
+
+    fn scheduler_config() -> SchedulerConfig {
+        SchedulerConfig {
+            rate: 1000.,
+            burst: 100.,
+            max_in_flight: 1,
+            prefix_spacing: Duration::ZERO,
+        }
+    }
+
+    fn scheduler() -> Scheduler {
+        Scheduler::new(scheduler_config())
+    }
+
+    /// A port nothing listens on, the kernel answers a SYN to it with a RST.
+    fn closed_port() -> u16 {
+        TcpListener::bind((LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// A listener whose accept queue is full, so the kernel drops further SYNs like a
+    /// filtering firewall would.
+    fn silent_port() -> (Socket, Vec<TcpStream>, u16) {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&SocketAddr::new(LOCALHOST, 0).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(stream) = TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+            queued.push(stream);
+        }
+        (listener, queued, addr.port())
+    }
+
+    #[tokio::test]
+    async fn record_from_listener() {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pinger = Pinger::new(config(vec![port]), scheduler_config()).unwrap();
+
+        let record = RecordWithGeolocation {
+            ip: LOCALHOST.to_string(),
+            location: "Cologne".to_string(),
+            country: "DE".to_string(),
+            latitude: None,
+            longitude: None,
+            spread: 0.,
+            providers: Vec::new(),
+        };
+        let record = pinger.spawn(record).unwrap().await.unwrap().unwrap();
+        assert_eq!(record.method, ProbeMethod::Tcp);
+        assert_eq!(record.location, "Cologne");
+        let stats = record.stats.unwrap();
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.samples.len(), 3);
+        assert_eq!(stats.loss, 0.);
+        assert_eq!(record.time, stats.min);
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_a_sample() {
+        let addr = SocketAddr::new(LOCALHOST, closed_port());
+        let rtt = connect_rtt(addr, Duration::from_secs(1), &SocketOptions::default()).await;
+        assert!(rtt.is_ok(), "{rtt:?}");
+    }
+
+    #[tokio::test]
+    async fn ports_are_tried_in_order() {
+        let (_listener, _queued, silent) = silent_port();
+        let first = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        first.set_nonblocking(true).unwrap();
+        let second = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        second.set_nonblocking(true).unwrap();
+        let ports = vec![
+            silent,
+            first.local_addr().unwrap().port(),
+            second.local_addr().unwrap().port(),
+        ];
+
+        let results = probe(LOCALHOST, &config(ports), &scheduler()).await;
+        assert_eq!(results.samples.len(), 3);
+        assert_eq!(first.incoming().take_while(Result::is_ok).count(), 3);
+        assert!(second.accept().is_err());
+    }
+
+    #[tokio::test]
+    async fn no_port_answers() {
+        let (_listener, _queued, silent) = silent_port();
+        let results = probe(LOCALHOST, &config(vec![silent, silent]), &scheduler()).await;
+        assert!(results.samples.is_empty());
+        assert!(matches!(
+            results.failures[..],
+            [FailureReason::Timeout, FailureReason::Timeout]
+        ));
+    }
+}
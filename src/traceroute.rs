@@ -0,0 +1,386 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use std::{fs::File, io::BufReader};
+
+use futures::{stream, StreamExt};
+use rand::random;
+use tokio::time;
+
+use crate::config::{env_list, env_or};
+use crate::distances::vantage_point;
+use crate::probers::RawIcmpSocket;
+use crate::structs::{Hop, RecordWithGeolocation, RecordWithPath};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceConfig {
+    pub max_hops: u8,
+    /// Probes sent per TTL.
+    pub probes: u16,
+    pub timeout: Duration,
+    /// Keep the ICMP checksum (which load balancers hash like a port) constant.
+    pub paris: bool,
+}
+
+impl TraceConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            max_hops: env_or("TRACE_MAX_HOPS", 30)?,
+            probes: env_or("TRACE_PROBES", 3)?,
+            timeout: Duration::from_millis(env_or("TRACE_TIMEOUT_MS", 1000)?),
+            paris: env_or("TRACE_PARIS", true)?,
+        };
+        anyhow::ensure!(config.probes >= 1, "TRACE_PROBES must be at least 1");
+        anyhow::ensure!(config.max_hops >= 1, "TRACE_MAX_HOPS must be at least 1");
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyKind {
+    EchoReply,
+    TimeExceeded,
+    /// Destination unreachable with its ICMP code.
+    Unreachable(u8),
+}
+
+const PAYLOAD_SIZE: usize = 32;
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn echo_request(ip: IpAddr, identifier: u16, sequence: u16, paris: bool) -> Vec<u8> {
+    let kind = match ip {
+        IpAddr::V4(_) => 8,
+        IpAddr::V6(_) => 128,
+    };
+    let mut packet = vec![0u8; 8 + PAYLOAD_SIZE];
+    packet[0] = kind;
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    if paris {
+        // sequence + !sequence is constant in ones' complement, so is the checksum
+        packet[8..10].copy_from_slice(&(!sequence).to_be_bytes());
+    }
+    if ip.is_ipv4() {
+        // the kernel fills in the ICMPv6 checksum
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+/// An ICMP message about an echo request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    pub kind: ReplyKind,
+    pub identifier: u16,
+    pub sequence: u16,
+    /// Destination of the request quoted by an error, `None` for echo replies.
+    pub destination: Option<IpAddr>,
+}
+
+/// Extracts the echo request a reply refers to. `ip` only selects the address family.
+pub fn parse_reply(ip: IpAddr, buf: &[u8]) -> Option<Reply> {
+    let ids = |icmp: &[u8]| -> Option<(u16, u16)> {
+        Some((
+            u16::from_be_bytes(icmp.get(4..6)?.try_into().ok()?),
+            u16::from_be_bytes(icmp.get(6..8)?.try_into().ok()?),
+        ))
+    };
+
+    // the error quotes the IP header of the request followed by its ICMP header
+    let (icmp, destination, inner_icmp) = match ip {
+        IpAddr::V4(_) => {
+            // raw IPv4 sockets deliver the IP header as well
+            let icmp = buf.get(usize::from(buf.first()? & 0x0f) * 4..)?;
+            let inner = icmp.get(8..)?;
+            let destination = inner
+                .get(16..20)
+                .and_then(|d| <[u8; 4]>::try_from(d).ok())
+                .map(IpAddr::from);
+            let inner_icmp = inner
+                .first()
+                .and_then(|b| inner.get(usize::from(b & 0x0f) * 4..));
+            (icmp, destination, inner_icmp)
+        }
+        IpAddr::V6(_) => {
+            let destination = buf
+                .get(32..48)
+                .and_then(|d| <[u8; 16]>::try_from(d).ok())
+                .map(IpAddr::from);
+            (buf, destination, buf.get(48..))
+        }
+    };
+
+    let kind = match (ip, icmp.first()?) {
+        (IpAddr::V4(_), 0) | (IpAddr::V6(_), 129) => {
+            let (identifier, sequence) = ids(icmp)?;
+            return Some(Reply {
+                kind: ReplyKind::EchoReply,
+                identifier,
+                sequence,
+                destination: None,
+            });
+        }
+        (IpAddr::V4(_), 11) | (IpAddr::V6(_), 3) => ReplyKind::TimeExceeded,
+        (IpAddr::V4(_), 3) | (IpAddr::V6(_), 1) => ReplyKind::Unreachable(*icmp.get(1)?),
+        _ => return None,
+    };
+    let (identifier, sequence) = ids(inner_icmp?)?;
+    Some(Reply {
+        kind,
+        identifier,
+        sequence,
+        destination: Some(destination?),
+    })
+}
+
+/// Sends one echo request with the socket's current TTL and waits for the reply to it.
+async fn probe(
+    socket: &RawIcmpSocket,
+    ip: IpAddr,
+    identifier: u16,
+    sequence: u16,
+    config: &TraceConfig,
+) -> anyhow::Result<Option<(IpAddr, Duration, ReplyKind)>> {
+    let packet = echo_request(ip, identifier, sequence, config.paris);
+    let sent = Instant::now();
+    socket.send_to(&packet, ip).await?;
+
+    let mut buf = [0u8; 1500];
+    let deadline = sent + config.timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Ok(received) = time::timeout(remaining, socket.recv_from(&mut buf)).await else {
+            return Ok(None);
+        };
+        let (size, from) = received?;
+        // raw sockets see all ICMP traffic of the host, only take replies to this probe
+        if let Some(reply) = parse_reply(ip, &buf[..size]) {
+            if reply.identifier == identifier
+                && reply.sequence == sequence
+                && reply.destination.is_none_or(|d| d == ip)
+            {
+                return Ok(Some((from, sent.elapsed(), reply.kind)));
+            }
+        }
+    }
+}
+
+/// Probes `ip` with increasing TTLs until it answers or `max_hops` is reached.
+pub async fn trace(ip: IpAddr, config: &TraceConfig) -> anyhow::Result<(bool, Vec<Hop>)> {
+    let socket = RawIcmpSocket::open(ip)?;
+
+    let identifier = random();
+    let mut sequence = random::<u16>();
+    let mut hops = Vec::new();
+    let mut reached = false;
+    let mut done = false;
+
+    for ttl in 1..=config.max_hops {
+        match ip {
+            IpAddr::V4(_) => socket.socket().set_ttl(u32::from(ttl))?,
+            IpAddr::V6(_) => socket.socket().set_unicast_hops_v6(u32::from(ttl))?,
+        }
+
+        let mut address = None;
+        let mut samples = Vec::new();
+        for _ in 0..config.probes {
+            sequence = sequence.wrapping_add(1);
+            if let Some((from, rtt, kind)) =
+                probe(&socket, ip, identifier, sequence, config).await?
+            {
+                address.get_or_insert(from);
+                samples.push(rtt.as_secs_f64());
+                reached |= from == ip;
+                // unreachable from a router ends the path just like an answer from the target
+                done |= kind != ReplyKind::TimeExceeded;
+            }
+        }
+
+        hops.push(Hop {
+            ttl,
+            address: address.map(|a| a.to_string()),
+            rtt: samples.iter().copied().reduce(f64::min),
+            loss: (usize::from(config.probes) - samples.len()) as f64 / f64::from(config.probes)
+                * 100f64,
+            samples,
+        });
+
+        if done {
+            break;
+        }
+    }
+
+    Ok((reached, hops))
+}
+
+pub async fn trace_ips() -> anyhow::Result<()> {
+    let input = File::open("./with_geolocations.json")?;
+    let records: Vec<RecordWithGeolocation> = serde_json::from_reader(BufReader::new(input))?;
+
+    let config = TraceConfig::from_env()?;
+    let concurrency: usize = env_or("TRACE_CONCURRENCY", 8)?;
+    let origin = vantage_point()?;
+    // tracing every target takes long, usually only the outliers are interesting
+    let only = env_list("TRACE_IPS", "").into_iter().collect::<HashSet<_>>();
+
+    let tasks = records
+        .into_iter()
+        .filter(|r| only.is_empty() || only.contains(&r.ip))
+        .filter_map(|r| {
+            let ip: IpAddr = r.ip.parse().ok()?;
+            let origin = &origin;
+            Some(async move {
+                match trace(ip, &config).await {
+                    Ok((reached, hops)) => {
+                        println!("[+] {} done in {} hops.", ip, hops.len());
+                        Some(RecordWithPath {
+                            ip: r.ip,
+                            location: r.location,
+                            paris: config.paris,
+                            reached,
+                            hops,
+                            origin: Some(origin.clone()),
+                        })
+                    }
+                    Err(e) => {
+                        println!("Err: {} traceroute {}", ip, e);
+                        None
+                    }
+                }
+            })
+        });
+
+    let results = stream::iter(tasks)
+        .buffer_unordered(concurrency)
+        .filter_map(|r| async { r })
+        .collect::<Vec<_>>()
+        .await;
+
+    let output = File::create("./paths.json")?;
+    serde_json::to_writer_pretty(output, &results)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const TARGET: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    const TARGET_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+    /// An IPv4 header with `options` words of options.
+    fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, options: u8) -> Vec<u8> {
+        let mut header = vec![0x45 + options, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0];
+        header.extend(src.octets());
+        header.extend(dst.octets());
+        header.extend(vec![0; usize::from(options) * 4]);
+        header
+    }
+
+    #[test]
+    fn paris_checksum_is_constant() {
+        let ip = IpAddr::V4(TARGET);
+        let checksums = |paris| {
+            [0, 1, 2, 255, 0x8000, u16::MAX]
+                .into_iter()
+                .map(|sequence| {
+                    let packet = echo_request(ip, 0x1234, sequence, paris);
+                    assert_eq!(packet.len(), 8 + PAYLOAD_SIZE);
+                    assert_eq!((packet[0], &packet[6..8]), (8, &sequence.to_be_bytes()[..]));
+                    // a valid checksum sums to zero
+                    assert_eq!(checksum(&packet), 0);
+                    u16::from_be_bytes([packet[2], packet[3]])
+                })
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(checksums(true).len(), 1);
+        // 0 and 0xffff are both zero in ones' complement
+        assert_eq!(checksums(false).len(), 5);
+
+        let v6 = echo_request(IpAddr::V6(TARGET_V6), 0x1234, 7, true);
+        assert_eq!((v6[0], &v6[2..4]), (128, &[0, 0][..]));
+    }
+
+    #[test]
+    fn time_exceeded_quotes_the_request() {
+        let request = echo_request(IpAddr::V4(TARGET), 0x1234, 5, true);
+        // the router's header carries options, so does the quoted one
+        let mut packet = ipv4(ROUTER, Ipv4Addr::new(10, 0, 0, 2), 1);
+        packet.extend([11, 0, 0, 0, 0, 0, 0, 0]);
+        packet.extend(ipv4(Ipv4Addr::new(10, 0, 0, 2), TARGET, 2));
+        packet.extend(&request[..8]);
+        assert_eq!(
+            parse_reply(IpAddr::V4(TARGET), &packet),
+            Some(Reply {
+                kind: ReplyKind::TimeExceeded,
+                identifier: 0x1234,
+                sequence: 5,
+                destination: Some(IpAddr::V4(TARGET)),
+            })
+        );
+
+        // unreachable with code 13 and only part of the quoted ICMP header
+        let mut packet = ipv4(ROUTER, Ipv4Addr::new(10, 0, 0, 2), 0);
+        packet.extend([3, 13, 0, 0, 0, 0, 0, 0]);
+        packet.extend(ipv4(Ipv4Addr::new(10, 0, 0, 2), TARGET, 0));
+        packet.extend(&request[..6]);
+        assert_eq!(parse_reply(IpAddr::V4(TARGET), &packet), None);
+        packet.extend(&request[6..8]);
+        let reply = parse_reply(IpAddr::V4(TARGET), &packet).unwrap();
+        assert_eq!(reply.kind, ReplyKind::Unreachable(13));
+    }
+
+    #[test]
+    fn echo_replies() {
+        let mut packet = ipv4(TARGET, Ipv4Addr::new(10, 0, 0, 2), 0);
+        packet.extend([0, 0, 0, 0, 0x12, 0x34, 0, 9]);
+        assert_eq!(
+            parse_reply(IpAddr::V4(TARGET), &packet),
+            Some(Reply {
+                kind: ReplyKind::EchoReply,
+                identifier: 0x1234,
+                sequence: 9,
+                destination: None,
+            })
+        );
+
+        // raw ICMPv6 sockets deliver the ICMP message only
+        let reply = [129, 0, 0, 0, 0x12, 0x34, 0, 9];
+        let reply = parse_reply(IpAddr::V6(TARGET_V6), &reply).unwrap();
+        assert_eq!((reply.kind, reply.sequence), (ReplyKind::EchoReply, 9));
+    }
+
+    #[test]
+    fn time_exceeded_v6() {
+        let mut packet = vec![3, 0, 0, 0, 0, 0, 0, 0];
+        let mut inner = vec![0x60, 0, 0, 0, 0, 8, 58, 1];
+        inner.extend([0; 16]);
+        inner.extend(TARGET_V6.octets());
+        packet.extend(inner);
+        packet.extend(&echo_request(IpAddr::V6(TARGET_V6), 0x1234, 3, true)[..8]);
+        assert_eq!(
+            parse_reply(IpAddr::V6(TARGET_V6), &packet),
+            Some(Reply {
+                kind: ReplyKind::TimeExceeded,
+                identifier: 0x1234,
+                sequence: 3,
+                destination: Some(IpAddr::V6(TARGET_V6)),
+            })
+        );
+    }
+}
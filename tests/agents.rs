@@ -0,0 +1,234 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use serde_json::{json, Value};
+
+fn pinger(dir: &Path, stage: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_pinger"));
+    command.arg(stage).current_dir(dir);
+    command
+}
+
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pinger-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join(".env"), "").unwrap();
+    dir
+}
+
+fn read_json(path: PathBuf) -> Value {
+    serde_json::from_slice(&fs::read(&path).unwrap()).unwrap()
+}
+
+/// A collector on a free port, killed when dropped.
+struct Collector {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    /// Printed before it started listening.
+    startup: Vec<String>,
+    url: String,
+}
+
+impl Collector {
+    fn start(dir: &Path) -> Self {
+        let mut child = pinger(dir, "collector")
+            .env("COLLECTOR_ADDR", "127.0.0.1:0")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut startup = Vec::new();
+        let url = loop {
+            let mut line = String::new();
+            assert!(stdout.read_line(&mut line).unwrap() > 0, "collector exited");
+            if let Some(rest) = line.strip_prefix("collecting on ") {
+                break rest.split(',').next().unwrap().to_string();
+            }
+            startup.push(line.trim_end().to_string());
+        };
+        Self {
+            child,
+            stdout,
+            startup,
+            url,
+        }
+    }
+
+    /// Posts `body` to `/results` and returns the response.
+    fn post(&self, body: &str) -> String {
+        let mut stream = TcpStream::connect(self.url.trim_start_matches("http://")).unwrap();
+        write!(
+            stream,
+            "POST /results HTTP/1.1\r\nHost: collector\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn printed(&mut self, prefix: &str) -> String {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            assert!(
+                self.stdout.read_line(&mut line).unwrap() > 0,
+                "{prefix} not printed"
+            );
+            if line.starts_with(prefix) {
+                return line.trim_end().to_string();
+            }
+        }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn two_agents_report_to_a_collector() {
+    let dir = work_dir("agents");
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let targets = json!([{"ip": "127.0.0.1", "location": "Cologne", "country": "DE"}]);
+    fs::write(dir.join("with_geolocations.json"), targets.to_string()).unwrap();
+
+    let mut collector = Collector::start(&dir);
+    for (id, latitude, longitude) in [("fra", 50.11, 8.68), ("ams", 52.37, 4.90)] {
+        let output = pinger(&dir, "agent")
+            .env("COLLECTOR_URL", &collector.url)
+            .env("AGENT_ROUNDS", "1")
+            .env("ORIGIN_ID", id)
+            .env("ORIGIN_LATITUDE", latitude.to_string())
+            .env("ORIGIN_LONGITUDE", longitude.to_string())
+            .env("PING_METHOD", "tcp")
+            .env("TCP_PORTS", target.local_addr().unwrap().port().to_string())
+            .env("PING_COUNT", "2")
+            .env("PING_INTERVAL_MS", "10")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("sent 1 results"), "{stdout}");
+        assert_eq!(collector.printed(id), format!("{id}: 1 results"));
+    }
+
+    let origins = read_json(dir.join("origins.json"));
+    let origins = origins.as_array().unwrap();
+    assert_eq!(origins.len(), 2);
+    assert_eq!(origins[0]["id"], "ams");
+    assert_eq!(origins[0]["latitude"], 52.37);
+    assert_eq!(origins[1]["id"], "fra");
+    assert_eq!(origins[1]["longitude"], 8.68);
+    for origin in origins {
+        let times = read_json(dir.join(origin["times"].as_str().unwrap()));
+        assert_eq!(times.as_array().unwrap().len(), 1);
+    }
+
+    let merged = read_json(dir.join("with_times_merged.json"));
+    let merged = merged.as_array().unwrap();
+    assert_eq!(merged.len(), 2);
+    for (record, id) in merged.iter().zip(["ams", "fra"]) {
+        assert_eq!(record["ip"], "127.0.0.1");
+        assert_eq!(record["location"], "Cologne");
+        assert_eq!(record["method"], "tcp");
+        assert_eq!(record["origin"]["id"], id);
+        assert_eq!(record["stats"]["sent"], 2);
+        assert!(record["time"].as_f64().unwrap() > 0.);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn collector_continues_origins_with_only_failures() {
+    let dir = work_dir("collector-restart");
+    let origins = json!([
+        {"id": "fra", "latitude": 50.11, "longitude": 8.68, "times": "collector/with_times_fra.json"}
+    ]);
+    fs::write(dir.join("origins.json"), origins.to_string()).unwrap();
+    fs::create_dir_all(dir.join("collector")).unwrap();
+    let failures = json!([
+        {"ip": "192.0.2.1", "location": "Cologne", "country": "DE", "method": "icmp", "reason": "timeout"}
+    ]);
+    fs::write(
+        dir.join("collector/failures_fra.json"),
+        failures.to_string(),
+    )
+    .unwrap();
+
+    let collector = Collector::start(&dir);
+    assert_eq!(
+        collector.startup,
+        ["continuing ./origins.json with 1 results from 1 origins"]
+    );
+    let sample = json!({
+        "timestamp": 0,
+        "ip": "192.0.2.2",
+        "location": "Berlin",
+        "method": "icmp",
+        "failure": {"reason": "timeout"},
+        "origin": {"id": "ams", "latitude": 52.37, "longitude": 4.90}
+    });
+    let response = collector.post(&format!("{sample}\n"));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    drop(collector);
+
+    let origins = read_json(dir.join("origins.json"));
+    let ids = origins
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|o| o["id"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, ["ams", "fra"]);
+    let failures = read_json(dir.join("collector/failures_fra.json"));
+    assert_eq!(failures[0]["ip"], "192.0.2.1");
+    assert_eq!(read_json(dir.join("with_times_merged.json")), json!([]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn collector_rejects_unusable_origin_ids() {
+    let dir = work_dir("collector-ids");
+    let collector = Collector::start(&dir);
+    let sample = |id: &str| {
+        json!({
+            "timestamp": 0,
+            "ip": "192.0.2.2",
+            "location": "Berlin",
+            "method": "icmp",
+            "failure": {"reason": "timeout"},
+            "origin": {"id": id, "latitude": 52.37, "longitude": 4.90}
+        })
+    };
+    for id in ["../fra", "", ".hidden", "a b"] {
+        let response = collector.post(&format!("{}\n", sample(id)));
+        assert!(response.starts_with("HTTP/1.1 400"), "{id}: {response}");
+    }
+    let response = collector.post(&"x".repeat((1 << 20) + 1));
+    assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    assert!(response.contains("line longer than"), "{response}");
+
+    // a rejected id leaves nothing behind that would fail later writes
+    let response = collector.post(&format!("{}\n", sample("atlas-123")));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    drop(collector);
+
+    let origins = read_json(dir.join("origins.json"));
+    assert_eq!(origins.as_array().unwrap().len(), 1);
+    assert_eq!(origins[0]["id"], "atlas-123");
+    let failures = read_json(dir.join("collector/failures_atlas-123.json"));
+    assert_eq!(failures[0]["ip"], "192.0.2.2");
+
+    fs::remove_dir_all(&dir).unwrap();
+}